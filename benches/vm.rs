@@ -0,0 +1,46 @@
+//! Times a tight counting loop of about ten million instructions.
+//!
+//! Run with `cargo bench`.
+
+use std::time::Instant;
+
+use parse::{
+    compiler::Code,
+    vm::{Value, Vm},
+};
+
+/// Each iteration runs the nine instructions from `Load(0)` to `Fr`.
+const ITERATIONS: i64 = 10_000_000 / 9;
+
+fn main() {
+    let code = vec![
+        Code::Push(0),
+        Code::Store(0),
+        // i = i + 1
+        Code::Load(0),
+        Code::Push(1),
+        Code::Add,
+        Code::Store(0),
+        // jump back to the increment while i < ITERATIONS
+        Code::Load(0),
+        Code::Push(ITERATIONS),
+        Code::Lt,
+        Code::Push(-9),
+        Code::Fr,
+        Code::Load(0),
+    ];
+
+    let mut vm = Vm::new(code);
+    let start = Instant::now();
+    vm.run().expect("the loop runs");
+    let elapsed = start.elapsed();
+    assert_eq!(vm.output(), Some(&Value::Int(ITERATIONS)));
+
+    let instructions = ITERATIONS * 9;
+    println!(
+        "counting loop: {} instructions in {:.3}ms ({:.1}M instructions/s)",
+        instructions,
+        elapsed.as_secs_f64() * 1000.0,
+        instructions as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
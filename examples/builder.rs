@@ -0,0 +1,33 @@
+//! Builds a program as an AST, with no source text, then compiles and runs it.
+//!
+//! Run with `cargo run --example builder`.
+
+use parse::{
+    vm::{Value, Vm},
+    CompileOptions, Compiler, Expr, Stmt,
+};
+
+fn main() {
+    // build square(x) { push x * x; }
+    // let n = 3 + 4;
+    // push square(n) - -1;
+    let program = Stmt::seq(vec![
+        Stmt::func("square", &["x"], vec![Stmt::push(Expr::var("x") * Expr::var("x"))]),
+        Stmt::assign("n", Expr::int(3) + Expr::int(4)),
+        Stmt::push(Expr::call("square", vec![Expr::var("n")]) - -Expr::int(1)),
+    ]);
+
+    let code = Compiler::with_options(CompileOptions::default()).compile(&program).expect("the program compiles");
+    println!("{:?}", code);
+
+    let mut vm = Vm::new(code);
+    vm.run().expect("the program runs");
+    assert_eq!(vm.output(), Some(&Value::Int(50)));
+    println!("{}", vm.output().unwrap());
+
+    let broken = Stmt::push(Expr::var("missing"));
+    match Compiler::new().compile(&broken) {
+        Ok(_) => unreachable!(),
+        Err(err) => println!("{}", err),
+    }
+}
@@ -0,0 +1,50 @@
+//! Adds a `beep n;` statement that calls the host function `beep` with `n`.
+//!
+//! Run with `cargo run --example custom_keyword`.
+
+use parse::{
+    compiler::{Code, CompileOptions, Compiler},
+    error::ParseError,
+    lexer::{CustomKeywordId, Lexer, LexerOptions, Token},
+    parser::{Parser, ParserOptions, Stmt},
+    source::SourceFile,
+};
+
+const BEEP: CustomKeywordId = CustomKeywordId(0);
+
+fn parse_custom(id: CustomKeywordId, parser: &mut Parser) -> Result<Stmt, ParseError> {
+    let args = if parser.lexer().lookahead == Some(Token::Semi) {
+        Vec::new()
+    } else {
+        vec![parser.parse_expr()?]
+    };
+    // The parser fills in where the keyword appears.
+    Ok(Stmt::Custom { id, args, line: 0, col: 0 })
+}
+
+fn lower_custom(id: CustomKeywordId) -> Vec<Code> {
+    match id {
+        BEEP => vec![Code::CallFunc("beep".to_owned())],
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let src = "push 1; beep 440 * 2; bbq;";
+    let options = LexerOptions::default().extra_keyword("beep", BEEP);
+    let lexer = Lexer::with_options(SourceFile::named("beep.egg", src), options);
+    let parser = Parser::with_options(lexer, ParserOptions::default().on_custom_stmt(parse_custom));
+    let ast = match parser.parse() {
+        Ok(ast) => ast,
+        Err(err) => {
+            print!("{}", err.report(&SourceFile::named("beep.egg", src), &LexerOptions::default()));
+            return;
+        }
+    };
+
+    let options = CompileOptions { lower_custom: Some(lower_custom), ..CompileOptions::default() };
+    match Compiler::with_options(options).compile(&ast) {
+        Ok(code) => println!("{:?}", code),
+        Err(err) => println!("{}", err),
+    }
+}
@@ -1,48 +1,994 @@
+use std::{collections::HashMap, fmt, mem, time::{Duration, Instant}};
+
 use crate::{
     parser::{Expr, Stmt},
-    lexer::Token
+    lexer::{CustomKeywordId, Token},
+    source::Span,
 };
 
-#[derive(Debug)]
-pub enum Code {
+/// Declares `Code` along with `Code::every`, one of each instruction in
+/// declaration order with default operands, so the instruction reference can't
+/// miss a variant.
+macro_rules! instructions {
+    ($($(#[$attr:meta])* $variant:ident $(($operand:ty))?),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Code {
+            $($(#[$attr])* $variant $(($operand))?,)*
+        }
+
+        impl Code {
+            fn every() -> Vec<Code> {
+                vec![$(Code::$variant $((<$operand>::default()))?),*]
+            }
+        }
+    };
+}
+
+instructions! {
     Axe,
     Chicken,
     Add,
     Fox,
     Rooster,
+    Compare,
     Pick,
     Peck,
     Fr,
     Bbq,
     Push(i64),
     PushFloat(f64),
-    PushVariable(String),
+    PushString(String),
+    Load(usize),
+    Store(usize),
+    /// Instruction index.
+    Hatch(usize),
+    Return,
+    /// Label number, removed by `finish`.
+    Label(usize),
+    /// Instruction index.
+    Jump(usize),
+    /// Instruction index.
+    JumpUnless(usize),
 
-    CallFunc(String), // function name
+    /// Function name.
+    CallFunc(String),
     Div,
+    Lt,
+    Gt,
+    Trap(String),
+}
+
+/// The kind of operand carried inline by an instruction.
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    None,
+    Int,
+    Float,
+    Name,
+    Message,
+    Slot,
+    String,
+    Address,
+}
+
+/// How many values an instruction pops and pushes; `Dynamic` when it depends
+/// on what is being called.
+#[derive(Debug, Clone, Copy)]
+pub enum StackEffect {
+    Fixed { pops: usize, pushes: usize },
+    Dynamic,
+}
+
+/// Reference metadata for one instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct InstrInfo {
+    pub mnemonic: &'static str,
+    pub operand: Operand,
+    pub effect: StackEffect,
+    /// Opcode in the original Chicken language, if the instruction has one.
+    /// `Push(n)` is encoded as opcode `n + 10`, which is reported as 10.
+    pub chicken_opcode: Option<u8>,
+    pub description: &'static str,
+}
+
+const fn fixed(pops: usize, pushes: usize) -> StackEffect {
+    StackEffect::Fixed { pops, pushes }
+}
+
+impl Code {
+    /// Returns the metadata for this instruction. The match is exhaustive, so a
+    /// new variant cannot be added without documenting it here.
+    pub fn info(&self) -> InstrInfo {
+        let (mnemonic, operand, effect, chicken_opcode, description) = match self {
+            Code::Axe => ("axe", Operand::None, fixed(0, 0), Some(0), "Stops execution."),
+            Code::Chicken => ("chicken", Operand::None, fixed(0, 1), Some(1), "Pushes the string \"chicken\"."),
+            Code::Add => ("add", Operand::None, fixed(2, 1), Some(2), "Pops two values and pushes their sum, or their concatenation if either is a string."),
+            Code::Fox => ("fox", Operand::None, fixed(2, 1), Some(3), "Pops b then a and pushes a - b."),
+            Code::Rooster => ("rooster", Operand::None, fixed(2, 1), Some(4), "Pops two values and pushes their product."),
+            Code::Compare => ("compare", Operand::None, fixed(2, 1), Some(5), "Pops two values and pushes 1 if they are equal, else 0."),
+            Code::Pick => ("pick", Operand::None, fixed(1, 1), Some(6), "Pops an index and pushes the value stored at it."),
+            Code::Peck => ("peck", Operand::None, fixed(2, 0), Some(7), "Pops an index and a value and stores the value at the index."),
+            Code::Fr => ("fr", Operand::None, fixed(2, 0), Some(8), "Pops an offset and a condition and jumps by the offset if the condition is truthy."),
+            Code::Bbq => ("bbq", Operand::None, fixed(1, 1), Some(9), "Pops a value and pushes the character with that code."),
+            Code::Push(_) => ("push", Operand::Int, fixed(0, 1), Some(10), "Pushes an integer constant."),
+            Code::PushFloat(_) => ("pushf", Operand::Float, fixed(0, 1), None, "Pushes a float constant."),
+            Code::PushString(_) => ("pushs", Operand::String, fixed(0, 1), None, "Pushes a string constant."),
+            Code::Load(_) => ("load", Operand::Slot, fixed(0, 1), None, "Pushes the value stored in a variable slot."),
+            Code::Store(_) => ("store", Operand::Slot, fixed(1, 0), None, "Pops a value and stores it in a variable slot."),
+            Code::Hatch(_) => ("hatch", Operand::Address, StackEffect::Dynamic, None, "Calls the function starting at an instruction index."),
+            Code::Return => ("return", Operand::None, fixed(0, 0), None, "Returns from the current function to the instruction after its hatch."),
+            Code::Label(_) => ("label", Operand::Address, fixed(0, 0), None, "Marks a jump target; linking removes it."),
+            Code::Jump(_) => ("jump", Operand::Address, fixed(0, 0), None, "Continues at an instruction index."),
+            Code::JumpUnless(_) => ("jumpz", Operand::Address, fixed(1, 0), None, "Pops a value and continues at an instruction index if it is falsy."),
+            Code::CallFunc(_) => ("call", Operand::Name, StackEffect::Dynamic, None, "Calls a function with its arguments on the stack."),
+            Code::Div => ("div", Operand::None, fixed(2, 1), None, "Pops b then a and pushes a / b."),
+            Code::Lt => ("lt", Operand::None, fixed(2, 1), None, "Pops b then a and pushes 1 if a < b, else 0."),
+            Code::Gt => ("gt", Operand::None, fixed(2, 1), None, "Pops b then a and pushes 1 if a > b, else 0."),
+            Code::Trap(_) => ("trap", Operand::Message, fixed(0, 0), None, "Stops with the error \"not yet implemented: message\"."),
+        };
+
+        InstrInfo { mnemonic, operand, effect, chicken_opcode, description }
+    }
+}
+
+/// Metadata for every instruction, in opcode order.
+pub fn isa() -> Vec<InstrInfo> {
+    Code::every().iter().map(Code::info).collect()
+}
+
+fn effect_text(effect: StackEffect) -> String {
+    match effect {
+        StackEffect::Fixed { pops, pushes } => format!("-{} +{}", pops, pushes),
+        StackEffect::Dynamic => "dynamic".to_owned(),
+    }
+}
+
+/// Renders the instruction reference as a Markdown table.
+pub fn isa_markdown() -> String {
+    let mut out = String::from("|Mnemonic|Operand|Stack|Chicken|Description|\n|---|---|---|---|---|\n");
+    for info in isa() {
+        out += &format!(
+            "|{}|{:?}|{}|{}|{}|\n",
+            info.mnemonic,
+            info.operand,
+            effect_text(info.effect),
+            info.chicken_opcode.map_or("-".to_owned(), |op| op.to_string()),
+            info.description,
+        );
+    }
+    out += "\n";
+    out += STRING_POLICY;
+    out += "\n";
+    out
+}
+
+/// How string values relate to the instruction set, appended to the
+/// instruction reference.
+pub const STRING_POLICY: &str = "Strings are values. pushs pushes a string literal, chicken pushes \"chicken\" \
+and bbq pushes a one-character string. add concatenates when either operand is a string, compare tests \
+equality, and every other arithmetic or ordering instruction fails at run time on a string operand; the parser \
+rejects such uses when an operand is a string literal. Under --char-literal-coercion a one-character literal used \
+as such an operand, or pushed right before bbq, is its code point instead.";
+
+/// Renders the instruction reference as a JSON array.
+pub fn isa_json() -> String {
+    let entries: Vec<_> = isa()
+        .iter()
+        .map(|info| {
+            format!(
+                "  {{\"mnemonic\": {:?}, \"operand\": {:?}, \"stack\": {:?}, \"chicken\": {}, \"description\": {:?}}}",
+                info.mnemonic,
+                format!("{:?}", info.operand),
+                effect_text(info.effect),
+                info.chicken_opcode.map_or("null".to_owned(), |op| op.to_string()),
+                info.description,
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+impl Code {
+    /// Number of "chicken" words the Chicken encoding of this instruction
+    /// takes, or `None` if it has no Chicken encoding.
+    pub fn chicken_words(&self) -> Option<usize> {
+        match self {
+            Code::Push(n) => usize::try_from(*n).ok()?.checked_add(10),
+            code => code.info().chicken_opcode.map(usize::from),
+        }
+    }
+}
+
+/// Size statistics for compiled code, computed without emitting it.
+#[derive(Debug)]
+pub struct SizeReport {
+    pub instructions: usize,
+    /// Instruction counts per mnemonic, in opcode order, omitting zeroes.
+    pub by_mnemonic: Vec<(&'static str, usize)>,
+    /// Instruction counts for the top level, then for each build in the
+    /// order they were laid out. Empty unless made by `with_origins`.
+    pub by_function: Vec<(String, usize)>,
+    /// Instruction counts per file and line, in statement order, counting
+    /// each instruction at the line its statement starts on, so a build's
+    /// body counts at the line of its `build`. Empty unless made by
+    /// `with_origins`.
+    pub by_line: Vec<(String, usize, usize)>,
+    /// Total words the chicken backend would emit, or `None` if some
+    /// instruction has no Chicken encoding.
+    pub chicken_words: Option<usize>,
+}
+
+impl SizeReport {
+    pub fn new(code: &[Code]) -> Self {
+        let by_mnemonic = isa()
+            .iter()
+            .map(|info| (info.mnemonic, code.iter().filter(|c| c.info().mnemonic == info.mnemonic).count()))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        let chicken_words = code.iter().map(Code::chicken_words).sum();
+
+        SizeReport {
+            instructions: code.len(),
+            by_mnemonic,
+            by_function: Vec::new(),
+            by_line: Vec::new(),
+            chicken_words,
+        }
+    }
+
+    /// Like `new`, with the breakdowns by function and by line. `origins` is
+    /// what `Compiler::finish_with_origins` returned with `code`, `stmts` are
+    /// the statements appended, and `lines` the file and line each starts on.
+    pub fn with_origins(code: &[Code], origins: &[Option<usize>], stmts: &[Stmt], lines: &[(&str, usize)]) -> Self {
+        let mut report = Self::new(code);
+        // Function bodies follow the axe ending the top level, the only
+        // instruction that belongs to no statement.
+        let top_level = origins.iter().position(Option::is_none).map_or(code.len(), |axe| axe + 1);
+        report.by_function.push(("(top level)".to_owned(), top_level));
+        for &origin in &origins[top_level..] {
+            let Some(Stmt::FuncDef { name, .. }) = origin.and_then(|origin| stmts.get(origin)) else { continue };
+            match report.by_function.last_mut() {
+                Some((last, count)) if last == name => *count += 1,
+                _ => report.by_function.push((name.clone(), 1)),
+            }
+        }
+        let mut counts = vec![0; lines.len()];
+        for &origin in origins.iter().flatten() {
+            counts[origin] += 1;
+        }
+        for (&(file, line), count) in lines.iter().zip(counts).filter(|&(_, count)| count > 0) {
+            match report.by_line.last_mut() {
+                Some((last_file, last_line, total)) if last_file == file && *last_line == line => *total += count,
+                _ => report.by_line.push((file.to_owned(), line, count)),
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Instructions: {}", self.instructions)?;
+        for (mnemonic, count) in &self.by_mnemonic {
+            writeln!(f, "    {:<8} {}", mnemonic, count)?;
+        }
+        if !self.by_function.is_empty() {
+            writeln!(f, "By function:")?;
+            for (name, count) in &self.by_function {
+                writeln!(f, "    {:<16} {}", name, count)?;
+            }
+        }
+        if !self.by_line.is_empty() {
+            writeln!(f, "By line:")?;
+            for (file, line, count) in &self.by_line {
+                writeln!(f, "    {:<16} {}", format!("{}:{}", file, line), count)?;
+            }
+        }
+        match self.chicken_words {
+            Some(words) => writeln!(f, "Chicken words: {}", words),
+            None => writeln!(f, "Chicken words: n/a (program uses instructions Chicken cannot encode)"),
+        }
+    }
+}
+
+/// Deepest nesting of add/fox/rooster tried when rebuilding a constant.
+const CHICKEN_SIZE_DEPTH: u32 = 4;
+/// Largest addend or subtrahend tried when rebuilding a constant.
+const CHICKEN_SIZE_OFFSET: i64 = 10;
+
+/// Replaces each `Push(n)` with an equivalent sequence of smaller pushes
+/// combined by `add`, `fox` and `rooster` when that takes fewer Chicken
+/// words, since `push n` alone costs `n + 10`. At most `budget` candidates
+/// and trial divisors are tried per constant. Every replacement is evaluated
+/// and kept only if it still yields `n`.
+pub fn optimize_chicken_size(code: Vec<Code>, budget: usize) -> Vec<Code> {
+    let mut out = Vec::with_capacity(code.len());
+    for code in code {
+        match code {
+            Code::Push(n) if n > 0 => {
+                let mut memo = HashMap::new();
+                let mut remaining = budget;
+                let (_, seq) = cheapest_constant(n, CHICKEN_SIZE_DEPTH, &mut remaining, &mut memo);
+                if eval_constant(&seq) == Some(n) {
+                    out.extend(seq);
+                } else {
+                    out.push(Code::Push(n));
+                }
+            }
+            code => out.push(code),
+        }
+    }
+    out
+}
+
+type ConstantMemo = HashMap<(i64, u32), (usize, Vec<Code>)>;
+
+/// Returns the fewest Chicken words found to build `n`, and the code for it.
+fn cheapest_constant(n: i64, depth: u32, budget: &mut usize, memo: &mut ConstantMemo) -> (usize, Vec<Code>) {
+    if let Some(found) = memo.get(&(n, depth)) {
+        return found.clone();
+    }
+
+    let mut best = (n as usize + 10, vec![Code::Push(n)]);
+    if depth > 0 {
+        let mut consider = |a: i64, b: i64, op: Code, budget: &mut usize| {
+            if *budget == 0 {
+                return;
+            }
+            *budget -= 1;
+            let (cost_a, seq_a) = cheapest_constant(a, depth - 1, budget, memo);
+            let (cost_b, seq_b) = cheapest_constant(b, depth - 1, budget, memo);
+            let cost = cost_a + cost_b + op.chicken_words().unwrap_or(usize::MAX / 4);
+            if cost < best.0 {
+                best = (cost, seq_a.into_iter().chain(seq_b).chain([op]).collect());
+            }
+        };
+
+        // Each divisor tried costs a unit of budget too, or a large prime
+        // would take about sqrt(n) steps.
+        let mut factor: i64 = 2;
+        while *budget > 0 && factor.checked_mul(factor).is_some_and(|square| square <= n) {
+            *budget -= 1;
+            if n % factor == 0 {
+                consider(factor, n / factor, Code::Rooster, budget);
+            }
+            factor += 1;
+        }
+        for offset in 1..=CHICKEN_SIZE_OFFSET.min(n - 1) {
+            consider(n - offset, offset, Code::Add, budget);
+        }
+        for offset in 1..=CHICKEN_SIZE_OFFSET {
+            if let Some(above) = n.checked_add(offset) {
+                consider(above, offset, Code::Fox, budget);
+            }
+        }
+    }
+
+    memo.insert((n, depth), best.clone());
+    best
+}
+
+/// Evaluates a sequence of integer pushes and arithmetic, or `None` if it
+/// contains anything else or leaves other than one value.
+fn eval_constant(code: &[Code]) -> Option<i64> {
+    let mut stack = Vec::new();
+    for code in code {
+        let value = match code {
+            Code::Push(n) => *n,
+            Code::Add | Code::Fox | Code::Rooster => {
+                let (b, a) = (stack.pop()?, stack.pop()?);
+                match code {
+                    Code::Add => i64::checked_add(a, b)?,
+                    Code::Fox => i64::checked_sub(a, b)?,
+                    _ => i64::checked_mul(a, b)?,
+                }
+            }
+            _ => return None,
+        };
+        stack.push(value);
+    }
+    match stack[..] {
+        [value] => Some(value),
+        _ => None,
+    }
+}
+
+/// A top-level statement that pops more values than the stack holds.
+#[derive(Debug)]
+pub struct StackUnderflow {
+    /// 1-based index of the offending top-level statement.
+    pub statement: usize,
+    pub mnemonic: &'static str,
+    pub pops: usize,
+    pub available: usize,
+    /// Stack depth before the statement started.
+    pub depth_before: usize,
+}
+
+impl fmt::Display for StackUnderflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Statement {}: this '{}' consumes {} values but only {} {} available at this point",
+            self.statement,
+            self.mnemonic,
+            self.pops,
+            self.available,
+            if self.available == 1 { "was" } else { "were" },
+        )?;
+        write!(f, "note: the stack depth was {} before this statement", self.depth_before)
+    }
+}
+
+/// Simulates the stack depth through top-level statements using each
+/// instruction's stack effect, starting from an empty stack. Each branch of a
+/// conditional is followed separately, and the statement after it starts from
+/// the shallowest depth a branch leaves. A `hatch` of a build takes the values
+/// its body needs and leaves its net effect. Checking stops at instructions
+/// whose effect is unknown, such as host calls and calls of recursive builds,
+/// once every branch has reached an `axe`, and at the first statement that
+/// doesn't compile, which the compiler reports.
+pub fn check_stack_balance(stmts: &[Stmt], options: &CompileOptions) -> Result<(), StackUnderflow> {
+    let mut compiler = Compiler::with_options(options.clone());
+    let mut compiled = Vec::new();
+    for stmt in stmts {
+        match compiler.append(stmt) {
+            Ok(code) => compiled.push(code.to_vec()),
+            Err(_) => break,
+        }
+    }
+    let mut calls = CallEffects { functions: &compiler.functions, found: vec![None; compiler.functions.len()] };
+    let mut depth = 0;
+    for (i, code) in compiled.iter().enumerate() {
+        let Some(walk) = walk_stack(code, depth, &mut calls) else { return Ok(()) };
+        if let Some((code, pops, available)) = walk.underflow {
+            return Err(StackUnderflow {
+                statement: i + 1,
+                mnemonic: code.info().mnemonic,
+                pops,
+                available: available as usize,
+                depth_before: depth as usize,
+            });
+        }
+        match walk.end {
+            Some(end) => depth = end,
+            None => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// How a call of a build changes the stack: its body pops down to `needs`
+/// values below where it started, its arguments included, and ends `net`
+/// values above or below that.
+#[derive(Debug, Clone, Copy)]
+struct CallEffect {
+    needs: isize,
+    net: isize,
+}
+
+/// Works out each build's `CallEffect` the first time a `hatch` needs it.
+struct CallEffects<'f> {
+    functions: &'f [Function],
+    /// `Some(None)` while the build is being worked out, and for good once
+    /// its effect turns out to be unknown.
+    found: Vec<Option<Option<CallEffect>>>,
+}
+
+impl CallEffects<'_> {
+    fn of(&mut self, function: usize) -> Option<CallEffect> {
+        if let Some(found) = self.found[function] {
+            return found;
+        }
+        self.found[function] = Some(None);
+        let functions = self.functions;
+        let effect = match functions[function].params {
+            Some(_) => walk_stack(&functions[function].code, 0, self)
+                .and_then(|walk| Some(CallEffect { needs: -walk.lowest, net: walk.end? })),
+            None => None,
+        };
+        self.found[function] = Some(effect);
+        effect
+    }
+}
+
+/// Where `walk_stack` left the stack.
+struct StackWalk<'c> {
+    /// The shallowest depth a path reaching the end leaves, or `None` when
+    /// every path stops at an `axe`.
+    end: Option<isize>,
+    /// The lowest depth any instruction popped down to.
+    lowest: isize,
+    /// The first instruction that popped below an empty stack, with how many
+    /// values it pops and the depth before it.
+    underflow: Option<(&'c Code, usize, isize)>,
+}
+
+/// Follows the stack effects of compiled but not yet finished code from
+/// `depth`, taking each branch of its jumps. `None` when some instruction's
+/// effect is unknown.
+fn walk_stack<'c>(code: &'c [Code], depth: isize, calls: &mut CallEffects) -> Option<StackWalk<'c>> {
+    // Until `finish`, jumps name labels, and a statement's labels are all
+    // inside its own code, as are a build's.
+    let labels: HashMap<usize, usize> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(at, code)| match code {
+            Code::Label(label) => Some((*label, at)),
+            _ => None,
+        })
+        .collect();
+    let target = |label| labels.get(label).copied().unwrap_or(code.len());
+    let mut walk = StackWalk { end: None, lowest: depth, underflow: None };
+    // Branches still to follow, as where each continues and the depth there.
+    let mut branches = vec![(0, depth)];
+    while let Some((mut at, mut depth)) = branches.pop() {
+        while let Some(instr) = code.get(at) {
+            if matches!(instr, Code::Axe) {
+                break;
+            }
+            let (pops, pushes) = match (instr, instr.info().effect) {
+                (Code::Hatch(function), _) => {
+                    let effect = calls.of(*function)?;
+                    (effect.needs, effect.needs + effect.net)
+                }
+                (_, StackEffect::Fixed { pops, pushes }) => (pops as isize, pushes as isize),
+                (_, StackEffect::Dynamic) => return None,
+            };
+            if depth - pops < 0 && walk.underflow.is_none() {
+                walk.underflow = Some((instr, pops as usize, depth));
+            }
+            walk.lowest = walk.lowest.min(depth - pops);
+            depth = depth - pops + pushes;
+            at = match instr {
+                Code::Jump(label) => target(label),
+                Code::JumpUnless(label) => {
+                    branches.push((target(label), depth));
+                    at + 1
+                }
+                _ => at + 1,
+            };
+        }
+        if at == code.len() {
+            walk.end = Some(walk.end.map_or(depth, |end| end.min(depth)));
+        }
+    }
+    Some(walk)
+}
+
+/// A program size measure that can be capped with `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    SourceBytes,
+    Tokens,
+    AstNodes,
+    Instructions,
 }
 
+impl Limit {
+    pub const ALL: [Limit; 4] = [Limit::SourceBytes, Limit::Tokens, Limit::AstNodes, Limit::Instructions];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::SourceBytes => "source bytes",
+            Limit::Tokens => "tokens",
+            Limit::AstNodes => "AST nodes",
+            Limit::Instructions => "instructions",
+        }
+    }
+}
+
+/// Upper bounds on program size, so untrusted input fails with a diagnostic
+/// instead of exhausting memory. The defaults are generous; embedders that
+/// compile untrusted code should tighten them.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub source_bytes: usize,
+    pub tokens: usize,
+    pub ast_nodes: usize,
+    pub instructions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            source_bytes: 64 << 20,
+            tokens: 16 << 20,
+            ast_nodes: 16 << 20,
+            instructions: 16 << 20,
+        }
+    }
+}
+
+impl Limits {
+    pub fn max(&self, limit: Limit) -> usize {
+        match limit {
+            Limit::SourceBytes => self.source_bytes,
+            Limit::Tokens => self.tokens,
+            Limit::AstNodes => self.ast_nodes,
+            Limit::Instructions => self.instructions,
+        }
+    }
+
+    pub fn set(&mut self, limit: Limit, max: usize) {
+        match limit {
+            Limit::SourceBytes => self.source_bytes = max,
+            Limit::Tokens => self.tokens = max,
+            Limit::AstNodes => self.ast_nodes = max,
+            Limit::Instructions => self.instructions = max,
+        }
+    }
+
+    pub fn check(&self, limit: Limit, found: usize) -> Result<(), LimitExceeded> {
+        let max = self.max(limit);
+        if found > max {
+            Err(LimitExceeded { limit, max, found })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for limit in Limit::ALL {
+            writeln!(f, "{:<14} {}", limit.name(), self.max(limit))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub max: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Program exceeds the limit of {} {} ({} found)",
+            self.max,
+            self.limit.name(),
+            self.found
+        )
+    }
+}
+
+/// A program the parser accepted that still can't be compiled, such as one
+/// reading a variable before any `let` assigns it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    /// 0 when the offending node was built in code rather than parsed.
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{} in generated code", self.message)
+        } else {
+            write!(f, "{} on line {} column {}", self.message, self.line, self.col)
+        }
+    }
+}
+
+/// The error for an operator the instruction set has no code for, which only
+/// a tree built in code can hold.
+fn unsupported_operator(what: &str, op: &Token) -> CompileError {
+    CompileError { message: format!("Unsupported {} {}", what, op.kind()), line: 0, col: 0 }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Flags enabling `@if flag ... @end` blocks; other blocks are dropped.
+    pub defines: Vec<String>,
+    pub limits: Limits,
+    /// Emits the code for a custom statement after its operands are pushed.
+    /// Without it, compiling a custom statement is an error.
+    pub lower_custom: Option<fn(CustomKeywordId) -> Vec<Code>>,
+    /// Functions the host provides at run time. Calls to them compile to
+    /// `call`; calls to any other name must match a `build`.
+    pub host_functions: Vec<String>,
+    /// When set, integer constants are rebuilt from smaller ones wherever that
+    /// takes fewer Chicken words, searching at most this many candidates per
+    /// constant.
+    pub chicken_size_budget: Option<usize>,
+}
+
+/// A custom AST pass registered with `CompilePhases`.
+pub type AstPass = Box<dyn FnMut(&mut Stmt)>;
+
+/// How long a registered pass took the last time it ran, and how many nodes
+/// the AST had afterwards.
+#[derive(Debug, Clone)]
+pub struct PassTiming {
+    pub name: String,
+    pub time: Duration,
+    pub nodes: usize,
+}
+
+/// Custom AST passes an embedder runs between the built-in phases. A program
+/// goes through lex, parse, these passes in registration order, then code
+/// generation; `Compiler::compile_with_phases` runs the last two.
+#[derive(Default)]
+pub struct CompilePhases {
+    passes: Vec<(String, AstPass)>,
+    timings: Vec<PassTiming>,
+}
+
+impl CompilePhases {
+    pub fn add_pass(&mut self, name: &str, pass: impl FnMut(&mut Stmt) + 'static) {
+        self.passes.push((name.to_owned(), Box::new(pass)));
+    }
+
+    /// Runs every pass over `ast` in registration order, timing each one.
+    pub fn run(&mut self, ast: &mut Stmt) {
+        self.timings.clear();
+        for (name, pass) in &mut self.passes {
+            let start = Instant::now();
+            pass(ast);
+            self.timings.push(PassTiming { name: name.clone(), time: start.elapsed(), nodes: ast.node_count() });
+        }
+    }
+
+    /// Each pass's timing from the last `run`, in the order they ran.
+    pub fn timings(&self) -> &[PassTiming] {
+        &self.timings
+    }
+}
+
+/// The innermost operator or call an instruction was compiled from: where
+/// it is in its statement's source, and what kind of expression it is, such
+/// as "negation".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExprSpan {
+    pub span: Span,
+    pub what: &'static str,
+}
+
+impl ExprSpan {
+    /// The span of an operator or call parsed from source, or `None` for
+    /// other expressions and nodes built in code.
+    fn of(expr: &Expr) -> Option<ExprSpan> {
+        let (span, what) = match expr {
+            Expr::BinOp { op, span, .. } => (*span, match op {
+                Token::Plus if expr.is_string() => "concatenation",
+                Token::Plus => "addition",
+                Token::Sub => "subtraction",
+                Token::Mul => "multiplication",
+                Token::Div => "division",
+                Token::Lt | Token::Gt => "comparison",
+                _ => return None,
+            }),
+            Expr::UnOp { op: Token::Sub, span, .. } => (*span, "negation"),
+            Expr::UnOp { op: Token::Plus, span, .. } => (*span, "unary plus"),
+            Expr::FunctionCall { span, .. } => (*span, "call"),
+            _ => return None,
+        };
+        (span.start < span.end).then_some(ExprSpan { span, what })
+    }
+}
+
+/// Linked code, with each instruction's origin and its innermost spanned
+/// expression, as returned by `Compiler::finish_with_spans`.
+pub type SpannedCode = (Vec<Code>, Vec<Option<usize>>, Vec<Option<ExprSpan>>);
+
+/// Tags each instruction of `code` with the first of `spans` whose range of
+/// instructions covers it, which is the innermost since expressions record
+/// their range after their operands do.
+fn tag_spans(code: Vec<Code>, spans: &[(usize, usize, ExprSpan)]) -> Vec<(Code, Option<ExprSpan>)> {
+    let mut tags = vec![None; code.len()];
+    for &(start, end, span) in spans {
+        for tag in &mut tags[start..end] {
+            tag.get_or_insert(span);
+        }
+    }
+    code.into_iter().zip(tags).collect()
+}
+
+/// A function defined with `build`, or only called so far.
+#[derive(Default)]
+struct Function {
+    /// `None` until the definition is compiled.
+    params: Option<usize>,
+    code: Vec<Code>,
+    /// The instruction ranges of `code` compiled from each spanned
+    /// expression, as in `Compiler::expr_spans`.
+    expr_spans: Vec<(usize, usize, ExprSpan)>,
+    /// Which `append` call compiled the definition.
+    origin: usize,
+}
+
+/// A call to a user function, checked against its definition in `finish`
+/// since the definition may come later.
+struct CallSite {
+    function: usize,
+    name: String,
+    args: usize,
+    line: usize,
+    col: usize,
+}
+
+#[derive(Default)]
 pub struct Compiler {
     code: Vec<Code>,
+    options: CompileOptions,
+    /// The slot of every variable assigned so far in the function being
+    /// compiled, or at the top level, numbered in order of first assignment.
+    slots: HashMap<String, usize>,
+    functions: Vec<Function>,
+    function_ids: HashMap<String, usize>,
+    calls: Vec<CallSite>,
+    /// How many labels have been handed out; jumps name a label until
+    /// `finish` resolves it to an address.
+    labels: usize,
+    /// Where the code of each `append` call starts in `code`.
+    appended: Vec<usize>,
+    /// The range of instructions in `code` compiled from each operator or
+    /// call with a span, innermost first.
+    expr_spans: Vec<(usize, usize, ExprSpan)>,
 }
 
 impl Compiler {
     pub fn new() -> Self {
+        Self::with_options(CompileOptions::default())
+    }
+
+    pub fn with_options(options: CompileOptions) -> Self {
         Self {
             code: Vec::new(),
+            options,
+            slots: HashMap::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            calls: Vec::new(),
+            labels: 0,
+            appended: Vec::new(),
+            expr_spans: Vec::new(),
+        }
+    }
+
+    pub fn compile(mut self, stmt: &Stmt) -> Result<Vec<Code>, CompileError> {
+        self.append(stmt)?;
+        self.finish()
+    }
+
+    /// Runs the passes in `phases` over `ast`, then compiles what they leave.
+    pub fn compile_with_phases(self, ast: &mut Stmt, phases: &mut CompilePhases) -> Result<Vec<Code>, CompileError> {
+        phases.run(ast);
+        self.compile(ast)
+    }
+
+    /// Appends the code for one more statement, for callers that compile a
+    /// program as it is parsed, and returns that code. Variables assigned and
+    /// functions defined by earlier statements stay in scope. Calls to user
+    /// functions and jumps are linked in `finish`, so until then `hatch` holds
+    /// the function's number and `jump` a label's number rather than an
+    /// address.
+    pub fn append(&mut self, stmt: &Stmt) -> Result<&[Code], CompileError> {
+        let start = self.code.len();
+        self.appended.push(start);
+        self.compile_stmt(stmt)?;
+        Ok(&self.code[start..])
+    }
+
+    /// Checks every call against its function's definition, then lays out the
+    /// program followed by an `axe` and the function bodies, removes the
+    /// labels, and points each `hatch` at its function and each jump at its
+    /// label's instruction.
+    pub fn finish(self) -> Result<Vec<Code>, CompileError> {
+        self.finish_with_origins().map(|(code, _)| code)
+    }
+
+    /// Like `finish`, but also returns, for each instruction, the number of
+    /// the `append` call that produced it, counting from 0. A function's body
+    /// belongs to the call that appended its `build`. The `axe` separating the
+    /// program from the function bodies belongs to none.
+    pub fn finish_with_origins(self) -> Result<(Vec<Code>, Vec<Option<usize>>), CompileError> {
+        self.finish_with_spans().map(|(code, origins, _)| (code, origins))
+    }
+
+    /// Like `finish_with_origins`, but also returns, for each instruction,
+    /// the innermost operator or call it was compiled from, if any.
+    pub fn finish_with_spans(self) -> Result<SpannedCode, CompileError> {
+        for call in &self.calls {
+            let message = match self.functions[call.function].params {
+                None => format!("Undefined function '{}'", call.name),
+                Some(params) if params != call.args => format!(
+                    "'{}' takes {} argument{} but {} {} given",
+                    call.name,
+                    params,
+                    if params == 1 { "" } else { "s" },
+                    call.args,
+                    if call.args == 1 { "was" } else { "were" },
+                ),
+                Some(_) => continue,
+            };
+            return Err(CompileError { message, line: call.line, col: call.col });
+        }
+
+        // Each instruction is optimized on its own, so the code replacing a
+        // push keeps the push's origin and span.
+        let budget = self.options.chicken_size_budget;
+        let optimize = |code: Vec<(Code, Option<ExprSpan>)>, origin| {
+            code.into_iter().flat_map(move |(code, span)| {
+                let code = match budget {
+                    Some(budget) => optimize_chicken_size(vec![code], budget),
+                    None => vec![code],
+                };
+                code.into_iter().map(move |code| (code, origin, span))
+            })
+        };
+        let mut laid_out = Vec::with_capacity(self.code.len());
+        let ends = self.appended.iter().skip(1).copied().chain([self.code.len()]);
+        let mut code = tag_spans(self.code, &self.expr_spans).into_iter();
+        for (origin, (start, end)) in self.appended.iter().zip(ends).enumerate() {
+            let segment = code.by_ref().take(end - start).collect();
+            laid_out.extend(optimize(segment, Some(origin)));
+        }
+        let mut starts = Vec::new();
+        if !self.functions.is_empty() {
+            laid_out.push((Code::Axe, None, None));
+        }
+        for function in self.functions {
+            starts.push(laid_out.len());
+            laid_out.extend(optimize(tag_spans(function.code, &function.expr_spans), Some(function.origin)));
+        }
+
+        // Labels take no space, so an index into `laid_out` moves back by the
+        // number of labels before it.
+        let mut moved = Vec::with_capacity(laid_out.len());
+        let mut labels = vec![0; self.labels];
+        let mut out = Vec::with_capacity(laid_out.len());
+        let mut origins = Vec::with_capacity(laid_out.len());
+        let mut spans = Vec::with_capacity(laid_out.len());
+        for (code, origin, span) in laid_out {
+            moved.push(out.len());
+            match code {
+                Code::Label(label) => labels[label] = out.len(),
+                code => {
+                    out.push(code);
+                    origins.push(origin);
+                    spans.push(span);
+                }
+            }
+        }
+        for code in &mut out {
+            match code {
+                Code::Hatch(target) => *target = moved[starts[*target]],
+                Code::Jump(target) | Code::JumpUnless(target) => *target = labels[*target],
+                _ => {}
+            }
+        }
+        Ok((out, origins, spans))
+    }
+
+    fn function_id(&mut self, name: &str) -> usize {
+        match self.function_ids.get(name) {
+            Some(&id) => id,
+            None => {
+                self.functions.push(Function::default());
+                self.function_ids.insert(name.to_owned(), self.functions.len() - 1);
+                self.functions.len() - 1
+            }
         }
     }
 
-    pub fn compile(mut self, expr: &Stmt) -> Vec<Code> {
-        self.compile_stmt(expr);
-        self.code
+    fn new_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
     }
 
-    fn compile_stmt(&mut self, stmt: &Stmt) {
+    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
         match stmt {
             Stmt::StmtSeq(seq) => {
                 for stmt in seq {
-                    self.compile_stmt(&stmt);
+                    self.compile_stmt(stmt)?;
                 }
             }
             Stmt::Axe => self.code.push(Code::Axe),
@@ -50,54 +996,165 @@ impl Compiler {
             Stmt::Add => self.code.push(Code::Add),
             Stmt::Fox => self.code.push(Code::Fox),
             Stmt::Rooster => self.code.push(Code::Rooster),
-            Stmt::Pick => self.code.push(Code::Pick),
-            Stmt::Peck => self.code.push(Code::Peck),
+            Stmt::Cmp => self.code.push(Code::Compare),
+            Stmt::CompareIf { then, otherwise } => {
+                let else_label = self.new_label();
+                self.code.push(Code::Compare);
+                self.code.push(Code::JumpUnless(else_label));
+                for stmt in then {
+                    self.compile_stmt(stmt)?;
+                }
+                if otherwise.is_empty() {
+                    self.code.push(Code::Label(else_label));
+                } else {
+                    let end_label = self.new_label();
+                    self.code.push(Code::Jump(end_label));
+                    self.code.push(Code::Label(else_label));
+                    for stmt in otherwise {
+                        self.compile_stmt(stmt)?;
+                    }
+                    self.code.push(Code::Label(end_label));
+                }
+            }
+            Stmt::Pick(imm) => {
+                if let Some(n) = imm {
+                    self.code.push(Code::Push(*n as i64));
+                }
+                self.code.push(Code::Pick);
+            }
+            Stmt::Peck(imm) => {
+                if let Some(n) = imm {
+                    self.code.push(Code::Push(*n as i64));
+                }
+                self.code.push(Code::Peck);
+            }
             Stmt::Fr => self.code.push(Code::Fr),
             Stmt::Bbq => self.code.push(Code::Bbq),
-            Stmt::Push(expr) => self.compile_expr(&expr),
-            _ => todo!()
+            Stmt::Push(expr) => self.compile_expr(expr)?,
+            Stmt::Todo(message) => self.code.push(Code::Trap(message.clone().unwrap_or_default())),
+            Stmt::CfgBlock { flag, body } => {
+                if self.options.defines.contains(flag) {
+                    for stmt in body {
+                        self.compile_stmt(stmt)?;
+                    }
+                }
+            }
+            Stmt::Custom { id, args, line, col } => {
+                let Some(lower) = self.options.lower_custom else {
+                    return Err(CompileError {
+                        message: format!("No lowering registered for custom statement {:?}", id),
+                        line: *line,
+                        col: *col,
+                    });
+                };
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                self.code.extend(lower(*id));
+            }
+            Stmt::Ass(name, expr) => {
+                self.compile_expr(expr)?;
+                let next = self.slots.len();
+                let slot = *self.slots.entry(name.clone()).or_insert(next);
+                self.code.push(Code::Store(slot));
+            }
+            Stmt::FuncDef { name, params, body, overrides, line, col } => {
+                // An override keeps the function's id, so hatches compiled
+                // against the earlier definition call the new body.
+                let id = self.function_id(name);
+                if self.functions[id].params.is_some() && !overrides {
+                    return Err(CompileError {
+                        message: format!("Function '{}' is already defined", name),
+                        line: *line,
+                        col: *col,
+                    });
+                }
+                self.functions[id].params = Some(params.len());
+                self.functions[id].origin = self.appended.len().saturating_sub(1);
+
+                // The body gets its own code and slots. Arguments arrive on the
+                // stack in order, so they are stored last parameter first.
+                let outer_code = mem::take(&mut self.code);
+                let outer_spans = mem::take(&mut self.expr_spans);
+                let outer_slots = mem::replace(
+                    &mut self.slots,
+                    params.iter().enumerate().map(|(slot, param)| (param.clone(), slot)).collect(),
+                );
+                self.code.extend((0..params.len()).rev().map(Code::Store));
+                let result = body.iter().try_for_each(|stmt| self.compile_stmt(stmt));
+                self.code.push(Code::Return);
+                self.functions[id].code = mem::replace(&mut self.code, outer_code);
+                self.functions[id].expr_spans = mem::replace(&mut self.expr_spans, outer_spans);
+                self.slots = outer_slots;
+                result?;
+            }
         }
+        Ok(())
     }
 
-    fn compile_expr(&mut self, expr: &Expr) {
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        let start = self.code.len();
         match expr {
             Expr::Int(num) => self.code.push(Code::Push(*num)),
             Expr::Float(num) => self.code.push(Code::PushFloat(*num)),
-            Expr::UnOp { op, operand } => {
+            Expr::String(text) => self.code.push(Code::PushString(text.clone())),
+            Expr::UnOp { op, operand, .. } => {
                 match op {
                     Token::Sub => {
                         self.compile_expr(&Expr::BinOp {
                             op: Token::Sub,
                             left: Box::new(Expr::Int(0)),
-                            right: operand.clone()
-                        });
+                            right: operand.clone(),
+                            span: Span::default(),
+                        })?;
                     }
-                    Token::Add => {
-                        self.compile_expr(operand);
+                    Token::Plus => {
+                        self.compile_expr(operand)?;
                     }
-                    _ => unreachable!()
+                    // The parser only makes the two above; anything else was
+                    // built in code.
+                    op => return Err(unsupported_operator("unary operator", op)),
                 }
             }
-            Expr::BinOp { op, left, right } => {
-                self.compile_expr(left);
-                self.compile_expr(right);
+            Expr::BinOp { op, left, right, .. } => {
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
                 match op {
                     Token::Plus => self.code.push(Code::Add),
                     Token::Sub => self.code.push(Code::Fox),
                     Token::Mul => self.code.push(Code::Rooster),
                     Token::Div => self.code.push(Code::Div),
-                    _ => panic!("Unexpected operator {:?}", op),
+                    Token::Lt => self.code.push(Code::Lt),
+                    Token::Gt => self.code.push(Code::Gt),
+                    op => return Err(unsupported_operator("operator", op)),
                 }
             }
-            Expr::FunctionCall { name, args } => {
+            Expr::FunctionCall { name, args, line, col, .. } => {
                 for arg in args {
-                    self.compile_expr(arg);
+                    self.compile_expr(arg)?;
+                }
+                if self.options.host_functions.contains(name) {
+                    self.code.push(Code::CallFunc(name.clone()));
+                } else {
+                    let function = self.function_id(name);
+                    self.calls.push(CallSite { function, name: name.clone(), args: args.len(), line: *line, col: *col });
+                    self.code.push(Code::Hatch(function));
                 }
-                self.code.push(Code::CallFunc(name.clone()));
-            }
-            Expr::Variable(name) => {
-                self.code.push(Code::PushVariable(name.clone()));
             }
+            Expr::Variable { name, line, col } => match self.slots.get(name) {
+                Some(&slot) => self.code.push(Code::Load(slot)),
+                None => {
+                    return Err(CompileError {
+                        message: format!("Undefined variable '{}'", name),
+                        line: *line,
+                        col: *col,
+                    })
+                }
+            },
+        }
+        if let Some(span) = ExprSpan::of(expr) {
+            self.expr_spans.push((start, self.code.len(), span));
         }
+        Ok(())
     }
 }
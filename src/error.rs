@@ -0,0 +1,123 @@
+use std::fmt;
+
+use crate::{
+    lexer::LexerOptions,
+    source::{SourceFile, Span},
+};
+
+/// A malformed token: an invalid character or escape, an unknown directive,
+/// an unterminated string, or a number that doesn't fit its type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+    pub line: usize,
+    pub col: usize,
+    /// The offending source text.
+    pub found: Box<str>,
+    /// Hints shown after the location, e.g. the ASCII character a confusable
+    /// stands in for.
+    pub notes: Vec<String>,
+}
+
+/// A secondary location shown under an error, such as where an unclosed
+/// bracket was opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub message: String,
+    pub span: Span,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// A syntax error, or a lexing error met while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+    pub line: usize,
+    pub col: usize,
+    /// The offending source text, empty at the end of input.
+    pub found: Box<str>,
+    pub labels: Vec<Label>,
+    /// Hints and what the parser was in the middle of, innermost first.
+    pub notes: Vec<String>,
+}
+
+impl LexError {
+    pub fn new(source: &SourceFile, span: Span, message: String) -> Self {
+        let (line, col) = source.line_col(span.start);
+        let found = source.text()[span.start..span.end].into();
+        LexError { message, span, line, col, found, notes: Vec::new() }
+    }
+
+    /// Renders the error as the command line shows it; see `ParseError::report`.
+    pub fn report(&self, source: &SourceFile, options: &LexerOptions) -> String {
+        ParseError::from(self.clone()).report(source, options)
+    }
+}
+
+impl Label {
+    pub fn new(source: &SourceFile, span: Span, message: String) -> Self {
+        let (line, col) = source.line_col(span.start);
+        Label { message, span, line, col }
+    }
+}
+
+impl ParseError {
+    pub fn new(source: &SourceFile, span: Span, message: String) -> Self {
+        LexError::new(source, span, message).into()
+    }
+
+    /// Renders the error as the command line shows it: the message and
+    /// position, the offending line with a caret under it, then any labelled
+    /// locations and notes.
+    pub fn report(&self, source: &SourceFile, options: &LexerOptions) -> String {
+        let mut out = format!("{}\n", self);
+        out += &render_location(source, self.span.start, options);
+        for label in &self.labels {
+            out += &format!("note: {} on line {} column {}\n", label.message, label.line, label.col);
+            out += &render_location(source, label.span.start, options);
+        }
+        for note in &self.notes {
+            out += &format!("note: {}\n", note);
+        }
+        out
+    }
+}
+
+fn render_location(source: &SourceFile, offset: usize, options: &LexerOptions) -> String {
+    let (line, col) = source.line_col(offset);
+    let mut out = String::new();
+    if let Some(file) = source.name() {
+        out += &format!(" --> {}:{}:{}\n", file, line, col);
+    }
+    out += &format!("\n{}\n", source.render_caret(offset, options.full_lines, options.tab_width));
+    out
+}
+
+impl From<LexError> for ParseError {
+    fn from(err: LexError) -> Self {
+        ParseError {
+            message: err.message,
+            span: err.span,
+            line: err.line,
+            col: err.col,
+            found: err.found,
+            labels: Vec::new(),
+            notes: err.notes,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {} column {}", self.message, self.line, self.col)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {} column {}", self.message, self.line, self.col)
+    }
+}
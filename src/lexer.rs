@@ -1,7 +1,15 @@
-use std::{str::Chars, process, iter};
+use std::{fmt, str::Chars};
+
+use crate::{
+    error::{Label, LexError, ParseError},
+    source::{SourceFile, Span, BOM},
+};
 
-use phf::phf_map;
 
+/// Identifies a keyword an embedder added through
+/// `LexerOptions::extra_keywords`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomKeywordId(pub u32);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -13,6 +21,56 @@ pub enum Token {
     Sub,
     Mul,
     Div,
+    Lt,
+    Gt,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Comma,
+    Eq,
+    Semi,
+    Let,
+    Hatch,
+    Build,
+    Override,
+    Push,
+    Top,
+
+    Axe,
+    Chicken,
+    Add,
+    Fox,
+    Rooster,
+    Cmp,
+    Pick,
+    Peck,
+    Fr,
+    Bbq,
+    Todo,
+
+    CfgIf,
+    CfgEnd,
+
+    Custom(CustomKeywordId),
+}
+
+/// The kind of a token without its payload, for expecting "any identifier"
+/// rather than one particular token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Int,
+    Float,
+    Identifier,
+    String,
+    Plus,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Gt,
     LParen,
     RParen,
     LBracket,
@@ -25,6 +83,7 @@ pub enum Token {
     Let,
     Hatch,
     Build,
+    Override,
     Push,
     Top,
 
@@ -38,11 +97,169 @@ pub enum Token {
     Peck,
     Fr,
     Bbq,
+    Todo,
+
+    CfgIf,
+    CfgEnd,
+
+    Custom,
+}
+
+impl Token {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            Token::Int(_) => TokenKind::Int,
+            Token::Float(_) => TokenKind::Float,
+            Token::Identifier(_) => TokenKind::Identifier,
+            Token::String(_) => TokenKind::String,
+            Token::Plus => TokenKind::Plus,
+            Token::Sub => TokenKind::Sub,
+            Token::Mul => TokenKind::Mul,
+            Token::Div => TokenKind::Div,
+            Token::Lt => TokenKind::Lt,
+            Token::Gt => TokenKind::Gt,
+            Token::LParen => TokenKind::LParen,
+            Token::RParen => TokenKind::RParen,
+            Token::LBracket => TokenKind::LBracket,
+            Token::RBracket => TokenKind::RBracket,
+            Token::LBrace => TokenKind::LBrace,
+            Token::RBrace => TokenKind::RBrace,
+            Token::Comma => TokenKind::Comma,
+            Token::Eq => TokenKind::Eq,
+            Token::Semi => TokenKind::Semi,
+            Token::Let => TokenKind::Let,
+            Token::Hatch => TokenKind::Hatch,
+            Token::Build => TokenKind::Build,
+            Token::Override => TokenKind::Override,
+            Token::Push => TokenKind::Push,
+            Token::Top => TokenKind::Top,
+            Token::Axe => TokenKind::Axe,
+            Token::Chicken => TokenKind::Chicken,
+            Token::Add => TokenKind::Add,
+            Token::Fox => TokenKind::Fox,
+            Token::Rooster => TokenKind::Rooster,
+            Token::Cmp => TokenKind::Cmp,
+            Token::Pick => TokenKind::Pick,
+            Token::Peck => TokenKind::Peck,
+            Token::Fr => TokenKind::Fr,
+            Token::Bbq => TokenKind::Bbq,
+            Token::Todo => TokenKind::Todo,
+            Token::CfgIf => TokenKind::CfgIf,
+            Token::CfgEnd => TokenKind::CfgEnd,
+            Token::Custom(_) => TokenKind::Custom,
+        }
+    }
+}
+
+impl Token {
+    /// Whether a statement can end with this token.
+    fn ends_statement(&self) -> bool {
+        matches!(
+            self,
+            Token::Int(_) | Token::Float(_) | Token::Identifier(_) | Token::String(_) | Token::RParen | Token::Top
+                | Token::Axe | Token::Chicken | Token::Add | Token::Fox | Token::Rooster | Token::Cmp
+                | Token::Pick | Token::Peck | Token::Fr | Token::Bbq | Token::Todo | Token::Custom(_)
+        )
+    }
+}
+
+fn closer_of(opener: TokenKind) -> TokenKind {
+    match opener {
+        TokenKind::LParen => TokenKind::RParen,
+        TokenKind::LBracket => TokenKind::RBracket,
+        _ => TokenKind::RBrace,
+    }
+}
+
+fn opener_of(closer: TokenKind) -> TokenKind {
+    match closer {
+        TokenKind::RParen => TokenKind::LParen,
+        TokenKind::RBracket => TokenKind::LBracket,
+        _ => TokenKind::LBrace,
+    }
 }
 
-static KEYWORDS: phf::Map<&'static str, Token> = phf_map! {
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            TokenKind::Int => "integer",
+            TokenKind::Float => "float",
+            TokenKind::Identifier => "identifier",
+            TokenKind::String => "string",
+            TokenKind::Plus => "'+'",
+            TokenKind::Sub => "'-'",
+            TokenKind::Mul => "'*'",
+            TokenKind::Div => "'/'",
+            TokenKind::Lt => "'<'",
+            TokenKind::Gt => "'>'",
+            TokenKind::LParen => "'('",
+            TokenKind::RParen => "')'",
+            TokenKind::LBracket => "'['",
+            TokenKind::RBracket => "']'",
+            TokenKind::LBrace => "'{'",
+            TokenKind::RBrace => "'}'",
+            TokenKind::Comma => "','",
+            TokenKind::Eq => "'='",
+            TokenKind::Semi => "';'",
+            TokenKind::Let => "keyword 'let'",
+            TokenKind::Hatch => "keyword 'hatch'",
+            TokenKind::Build => "keyword 'build'",
+            TokenKind::Override => "keyword 'override'",
+            TokenKind::Push => "keyword 'push'",
+            TokenKind::Top => "keyword 'TOP'",
+            TokenKind::Axe => "keyword 'axe'",
+            TokenKind::Chicken => "keyword 'chicken'",
+            TokenKind::Add => "keyword 'add'",
+            TokenKind::Fox => "keyword 'fox'",
+            TokenKind::Rooster => "keyword 'rooster'",
+            TokenKind::Cmp => "keyword 'compare'",
+            TokenKind::Pick => "keyword 'pick'",
+            TokenKind::Peck => "keyword 'peck'",
+            TokenKind::Fr => "keyword 'fr'",
+            TokenKind::Bbq => "keyword 'bbq'",
+            TokenKind::Todo => "keyword 'todo'",
+            TokenKind::CfgIf => "'@if'",
+            TokenKind::CfgEnd => "'@end'",
+            TokenKind::Custom => "custom keyword",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Declares `KEYWORDS`, looked up with `KEYWORDS.get(word)`. With the `phf`
+/// feature it is a perfect-hash map; without it, a `match` over the same
+/// entries, so the crate builds with no dependencies. `KEYWORD_NAMES` lists
+/// the same words in declaration order.
+macro_rules! keywords {
+    ($($word:literal => $tok:expr),* $(,)?) => {
+        /// Every built-in keyword, for documentation and syntax highlighting.
+        pub static KEYWORD_NAMES: &[&str] = &[$($word),*];
+
+        #[cfg(feature = "phf")]
+        static KEYWORDS: phf::Map<&'static str, Token> = phf::phf_map! { $($word => $tok),* };
+
+        #[cfg(not(feature = "phf"))]
+        static KEYWORDS: KeywordMatch = KeywordMatch;
+
+        #[cfg(not(feature = "phf"))]
+        struct KeywordMatch;
+
+        #[cfg(not(feature = "phf"))]
+        impl KeywordMatch {
+            fn get(&self, word: &str) -> Option<&'static Token> {
+                match word {
+                    $($word => Some(&$tok),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+keywords! {
     "let" => Token::Let,
     "build" => Token::Build,
+    "override" => Token::Override,
     "hatch" => Token::Hatch,
     "push" => Token::Push,
     "TOP" => Token::Top,
@@ -56,35 +273,200 @@ static KEYWORDS: phf::Map<&'static str, Token> = phf_map! {
     "pick" => Token::Pick,
     "peck" => Token::Peck,
     "fr" => Token::Fr,
-    "bbq" => Token::Bbq
-};
+    "bbq" => Token::Bbq,
+    "todo" => Token::Todo
+}
+
+/// Every `@` directive, by name without the `@`.
+pub static DIRECTIVES: &[(&str, Token)] = &[("if", Token::CfgIf), ("end", Token::CfgEnd)];
+
+/// Comment delimiters as (opener, closer); line comments have no closer and
+/// end at a line terminator. Block comments nest.
+pub const COMMENTS: &[(&str, Option<&str>)] = &[("//", None), ("/*", Some("*/"))];
+
+/// Characters commonly pasted in from rich-text editors, with their Unicode
+/// names and the ASCII characters they stand in for.
+pub static CONFUSABLES: &[(char, &str, char)] = &[
+    ('\u{201C}', "LEFT DOUBLE QUOTATION MARK", '"'),
+    ('\u{201D}', "RIGHT DOUBLE QUOTATION MARK", '"'),
+    ('\u{2018}', "LEFT SINGLE QUOTATION MARK", '\''),
+    ('\u{2019}', "RIGHT SINGLE QUOTATION MARK", '\''),
+    ('\u{00A0}', "NO-BREAK SPACE", ' '),
+    ('\u{2013}', "EN DASH", '-'),
+    ('\u{2014}', "EM DASH", '-'),
+    ('\u{2212}', "MINUS SIGN", '-'),
+    ('\u{FF08}', "FULLWIDTH LEFT PARENTHESIS", '('),
+    ('\u{FF09}', "FULLWIDTH RIGHT PARENTHESIS", ')'),
+];
+
+pub fn confusable(ch: char) -> Option<(&'static str, char)> {
+    CONFUSABLES.iter().find(|&&(c, _, _)| c == ch).map(|&(_, name, ascii)| (name, ascii))
+}
+
+/// Zero-width characters, with their Unicode names. They have no place in
+/// source text outside strings and comments, and are hard to spot once there.
+/// A byte order mark is only skipped at the very start of a file.
+pub static INVISIBLES: &[(char, &str)] = &[
+    ('\u{200B}', "ZERO WIDTH SPACE"),
+    ('\u{200C}', "ZERO WIDTH NON-JOINER"),
+    ('\u{200D}', "ZERO WIDTH JOINER"),
+    ('\u{2060}', "WORD JOINER"),
+    ('\u{FEFF}', "ZERO WIDTH NO-BREAK SPACE"),
+];
+
+pub fn invisible(ch: char) -> Option<&'static str> {
+    INVISIBLES.iter().find(|&&(c, _)| c == ch).map(|&(_, name)| name)
+}
+
+/// A confusable or invisible character replaced by `fix_confusables`.
+#[derive(Debug)]
+pub struct ConfusableFix {
+    /// Byte offset of the character in the original text.
+    pub offset: usize,
+    pub found: char,
+    pub name: &'static str,
+    /// The ASCII counterpart, or `None` if the character was removed.
+    pub replacement: Option<char>,
+}
+
+/// Replaces every confusable character with its ASCII counterpart and removes
+/// every invisible one, except a byte order mark at the start.
+pub fn fix_confusables(text: &str) -> (String, Vec<ConfusableFix>) {
+    let mut fixed = String::with_capacity(text.len());
+    let mut fixes = Vec::new();
+    for (offset, ch) in text.char_indices() {
+        if let Some((name, replacement)) = confusable(ch) {
+            fixed.push(replacement);
+            fixes.push(ConfusableFix { offset, found: ch, name, replacement: Some(replacement) });
+        } else if let Some(name) = invisible(ch).filter(|_| offset > 0) {
+            fixes.push(ConfusableFix { offset, found: ch, name, replacement: None });
+        } else {
+            fixed.push(ch);
+        }
+    }
+    (fixed, fixes)
+}
+
+/// Knobs controlling how source text is lexed and how lexer errors render.
+#[derive(Debug, Clone)]
+pub struct LexerOptions {
+    /// Echo whole source lines in diagnostics instead of a window around the
+    /// error position.
+    pub full_lines: bool,
+    /// Columns between tab stops when echoing source lines in diagnostics.
+    /// Reported column numbers still count a tab as one.
+    pub tab_width: usize,
+    /// A newline ends a statement whose `;` is missing, as in original
+    /// Eggsembly files.
+    pub newline_separators: bool,
+    /// Keywords match regardless of case, so `BBQ` is `bbq`.
+    pub case_insensitive_keywords: bool,
+    /// Keywords added by an embedder, lexed as `Token::Custom`. Standard
+    /// keywords take precedence.
+    pub extra_keywords: Vec<(String, CustomKeywordId)>,
+}
+
+impl Default for LexerOptions {
+    fn default() -> Self {
+        LexerOptions {
+            full_lines: false,
+            tab_width: 4,
+            newline_separators: false,
+            case_insensitive_keywords: false,
+            extra_keywords: Vec::new(),
+        }
+    }
+}
+
+impl LexerOptions {
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width;
+        self
+    }
+
+    pub fn full_lines(mut self, full_lines: bool) -> Self {
+        self.full_lines = full_lines;
+        self
+    }
+
+    pub fn newline_separators(mut self, newline_separators: bool) -> Self {
+        self.newline_separators = newline_separators;
+        self
+    }
+
+    pub fn case_insensitive_keywords(mut self, case_insensitive_keywords: bool) -> Self {
+        self.case_insensitive_keywords = case_insensitive_keywords;
+        self
+    }
+
+    pub fn extra_keyword(mut self, keyword: &str, id: CustomKeywordId) -> Self {
+        self.extra_keywords.push((keyword.to_owned(), id));
+        self
+    }
+
+    /// Enables every leniency needed for original Eggsembly files.
+    pub fn classic(self, classic: bool) -> Self {
+        self.newline_separators(classic).case_insensitive_keywords(classic)
+    }
+}
 
 pub struct Lexer<'a> {
-    input: &'a String,
+    source: SourceFile<'a>,
     chars: Chars<'a>,
     pub cur_char: Option<char>,
-    pub line: usize,
-    pub col: usize,
+    /// Byte offset of `cur_char`.
     pub pos: usize,
+    /// Byte offset where `lookahead` starts.
+    token_start: usize,
+    /// Kind and span of the last consumed token, for end-of-file
+    /// diagnostics.
+    prev: Option<(TokenKind, Span)>,
+    /// The error that stopped lexing. `lookahead` reads as the end of input
+    /// from then on, and whatever meets that end reports this instead.
+    error: Option<LexError>,
+    /// What the parser is in the middle of, innermost last, reported as notes
+    /// under each diagnostic.
+    context: Vec<String>,
+    options: LexerOptions,
+    /// Whether the last token lexed can end a statement, for classic mode's
+    /// newline separators.
+    ends_stmt: bool,
+    /// Whether the last token lexed was `@if`, whose flag ends no statement.
+    after_cfg_if: bool,
+    used_newline_separators: bool,
+    used_case_insensitive_keywords: bool,
+    /// Tokens lexed ahead by `buffer` with their spans, ending with the end of
+    /// input or where lexing failed.
+    buffered: Vec<(Option<Token>, Span)>,
+    /// Index in `buffered` of the token after the lookahead, once `buffer`
+    /// has run.
+    replay: Option<usize>,
     pub lookahead: Option<Token>,
 }
 
-pub struct LexerIterator<'a> {
-    lexer: &'a mut Lexer<'a>,
+pub struct LexerIterator<'l, 'a> {
+    lexer: &'l mut Lexer<'a>,
 }
 
-impl Iterator for LexerIterator<'_> {
-    type Item = Token;
+/// Yields tokens until the end of input or the first lexing error.
+impl Iterator for LexerIterator<'_, '_> {
+    type Item = Result<Token, LexError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.lexer.lex_token()
+        match self.lexer.lookahead.take() {
+            Some(tok) => {
+                self.lexer.step_token();
+                Some(Ok(tok))
+            }
+            None => self.lexer.error.take().map(Err),
+        }
     }
 }
 
-impl<'a> IntoIterator for &'a mut Lexer<'a> {
-    type Item = Token;
+impl<'l, 'a> IntoIterator for &'l mut Lexer<'a> {
+    type Item = Result<Token, LexError>;
 
-    type IntoIter = LexerIterator<'a>;
+    type IntoIter = LexerIterator<'l, 'a>;
 
     fn into_iter(self) -> Self::IntoIter {
         LexerIterator { lexer: self }
@@ -93,69 +475,279 @@ impl<'a> IntoIterator for &'a mut Lexer<'a> {
 
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &'a String) -> Self {
+    pub fn new(input: &'a str) -> Self {
+        Self::with_options(SourceFile::new(input), LexerOptions::default())
+    }
+
+    /// Creates a lexer whose diagnostics are labelled with `file`.
+    pub fn with_file(input: &'a str, file: &'a str) -> Self {
+        Self::with_options(SourceFile::named(file, input), LexerOptions::default())
+    }
+
+    pub fn with_options(source: SourceFile<'a>, options: LexerOptions) -> Self {
         let mut lexer = Lexer {
-            input,
-            chars: input.chars(),
+            chars: source.text().chars(),
+            source,
+            options,
             cur_char: None,
-            line: 1,
-            col: 0,
             pos: 0,
+            token_start: 0,
+            prev: None,
+            error: None,
+            context: Vec::new(),
+            ends_stmt: false,
+            after_cfg_if: false,
+            used_newline_separators: false,
+            used_case_insensitive_keywords: false,
+            buffered: Vec::new(),
+            replay: None,
             lookahead: None,
         };
         lexer.cur_char = lexer.chars.next();
-        lexer.lookahead = lexer.lex_token();
+        if lexer.cur_char == Some(BOM) {
+            lexer.step_chr();
+        }
+        lexer.step_token();
         lexer
     }
 
-    pub fn match_token(&mut self, expected: Token) -> bool {
-        if self.lookahead == Some(expected.clone()) {
-            self.lookahead = self.lex_token();
-            true
-        } else {
-            println!(
-                "Expected {:?} on line {} column {}, got {}",
-                expected,
-                self.line,
-                self.col,
-                match &self.lookahead {
-                    Some(t) => format!("{:?}", t),
-                    None => "None".to_owned()
-                }
-            );
-            self.point_error()
+    /// Describes the classic-mode leniencies this lexer has needed so far, so
+    /// users know what to change to compile without them.
+    pub fn classic_behaviors_used(&self) -> Vec<&'static str> {
+        let mut used = Vec::new();
+        if self.used_newline_separators {
+            used.push("newline statement separators");
+        }
+        if self.used_case_insensitive_keywords {
+            used.push("case-insensitive keywords");
+        }
+        used
+    }
+
+    pub fn source(&self) -> &SourceFile<'a> {
+        &self.source
+    }
+
+    /// Byte offset where the lookahead token starts, or the end of input.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// Line and column where the lookahead token starts.
+    pub fn token_pos(&self) -> (usize, usize) {
+        self.source.line_col(self.token_start)
+    }
+
+    /// Span of the lookahead token, empty at the end of input.
+    pub fn token_span(&self) -> Span {
+        Span { start: self.token_start, end: self.pos }
+    }
+
+    /// Span of the last consumed token, or the lookahead's before any.
+    pub fn prev_span(&self) -> Span {
+        self.prev.map_or(self.token_span(), |(_, span)| span)
+    }
+
+    /// Builds an error at `span`, noting what the parser was in the middle of.
+    pub fn error_at(&self, span: Span, message: String) -> ParseError {
+        let mut err = ParseError::new(&self.source, span, message);
+        err.notes.extend(self.context_notes());
+        err
+    }
+
+    fn context_notes(&self) -> impl Iterator<Item = String> + '_ {
+        self.context.iter().rev().map(|context| format!("while parsing {}", context))
+    }
+
+    /// Builds an error at the lookahead token.
+    pub fn error(&self, message: String) -> ParseError {
+        self.error_at(self.token_span(), message)
+    }
+
+    /// Takes the error that stopped lexing, if any, to report where the
+    /// parser would otherwise see the end of input.
+    pub fn take_error(&mut self) -> Option<ParseError> {
+        let mut err = ParseError::from(self.error.take()?);
+        err.notes.extend(self.context_notes());
+        Some(err)
+    }
+
+    /// Consumes the lookahead if it is exactly `expected`; meant for
+    /// punctuation and keywords.
+    pub fn expect_exact(&mut self, expected: &Token) -> Result<(), ParseError> {
+        if self.lookahead.as_ref() != Some(expected) {
+            return Err(self.expected_error(expected.kind()));
+        }
+        self.step_token();
+        Ok(())
+    }
+
+    /// Consumes and returns the lookahead if it is of kind `expected`, so the
+    /// caller can extract its payload.
+    pub fn expect_kind(&mut self, expected: TokenKind) -> Result<Token, ParseError> {
+        match self.lookahead.clone() {
+            Some(tok) if tok.kind() == expected => {
+                self.step_token();
+                Ok(tok)
+            }
+            _ => Err(self.expected_error(expected)),
+        }
+    }
+
+    fn expected_error(&mut self, expected: TokenKind) -> ParseError {
+        match &self.lookahead {
+            Some(tok) => self.error(format!("Expected {}, got {}", expected, tok.kind())),
+            None => self.eof_error(&expected.to_string()),
         }
     }
 
     pub fn step_token(&mut self) {
-        self.lookahead = self.lex_token();
+        if let Some(tok) = &self.lookahead {
+            self.prev = Some((tok.kind(), self.token_span()));
+        }
+        if let Some(next) = self.replay {
+            if let Some((tok, span)) = self.buffered.get(next) {
+                self.lookahead = tok.clone();
+                self.token_start = span.start;
+                self.pos = span.end;
+                self.replay = Some(next + 1);
+            }
+            return;
+        }
+        if self.error.is_some() {
+            self.lookahead = None;
+            return;
+        }
+        match self.lex_token() {
+            Ok(tok) => self.lookahead = tok,
+            Err(err) => {
+                self.lookahead = None;
+                self.error = Some(err);
+            }
+        }
     }
 
-    pub fn point_error(&self) -> ! {
-        let line_end = if let Some(n) = self.input[self.pos..].find('\n') {
-            self.pos + n - 1
-        } else {
-            self.input.len() - 1
-        };
+    /// Reports that input ended where `expected` should have followed the
+    /// last consumed token, pointing at that token rather than past the end
+    /// of the file. If lexing failed, that error is reported instead.
+    pub fn eof_error(&mut self, expected: &str) -> ParseError {
+        if let Some(err) = self.take_error() {
+            return err;
+        }
+        match self.prev {
+            Some((kind, span)) => {
+                self.error_at(span, format!("Expected {} after {}, found end of file", expected, kind))
+            }
+            None => self.error(format!("Expected {}, found end of file", expected)),
+        }
+    }
 
-        println!("\n{}", &self.input[self.pos - self.col + 1..=line_end]);
-        println!("{}^", iter::repeat(' ').take(self.col - 2).collect::<String>());
-        process::exit(1)
+    /// Lexes the rest of the input at once, calling `on_token` with each token
+    /// and its span, and stops at the first error, which `take_error` then
+    /// returns. The lexer goes on to replay these tokens, so whatever parses
+    /// them next doesn't lex the input again. Does nothing the second time.
+    pub fn buffer(&mut self, mut on_token: impl FnMut(&Token, Span)) {
+        if self.replay.is_some() {
+            return;
+        }
+        let prev = self.prev;
+        loop {
+            let span = self.token_span();
+            let tok = self.lookahead.take();
+            if let Some(tok) = &tok {
+                on_token(tok, span);
+            }
+            let end = tok.is_none();
+            self.buffered.push((tok, span));
+            if end {
+                break;
+            }
+            self.step_token();
+        }
+        self.prev = prev;
+        self.replay = Some(0);
+        self.step_token();
+    }
+
+    /// Checks that every bracket in the rest of the input is closed by its
+    /// matching bracket, before parsing reports the problem somewhere less
+    /// helpful. Errors name both the closer and the opener involved. The
+    /// input is buffered first, so parsing can go on from the lookahead.
+    /// Brackets left open where lexing failed are not reported, since the
+    /// lexing error is.
+    pub fn check_brackets(&mut self) -> Result<(), ParseError> {
+        self.buffer(|_, _| {});
+        let rest = &self.buffered[self.replay.map_or(0, |next| next - 1)..];
+        let mut open: Vec<(TokenKind, Span)> = Vec::new();
+        for (tok, span) in rest {
+            let (Some(tok), &span) = (tok, span) else { break };
+            match tok {
+                Token::LParen | Token::LBracket | Token::LBrace => open.push((tok.kind(), span)),
+                Token::RParen | Token::RBracket | Token::RBrace => match open.pop() {
+                    Some((opener, _)) if closer_of(opener) == tok.kind() => {}
+                    Some((opener, opened)) => {
+                        let mut err = self.error_at(
+                            span,
+                            format!("Mismatched {}, expected {}", tok.kind(), closer_of(opener)),
+                        );
+                        err.labels.push(Label::new(&self.source, opened, format!("unclosed {} opened", opener)));
+                        return Err(err);
+                    }
+                    None => {
+                        return Err(self.error_at(
+                            span,
+                            format!("No matching {} for this {}", opener_of(tok.kind()), tok.kind()),
+                        ));
+                    }
+                },
+                _ => {}
+            }
+        }
+        match open.pop() {
+            Some((opener, opened)) if self.error.is_none() => {
+                Err(self.error_at(opened, format!("Unclosed {} opened", opener)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Notes that diagnostics until the matching `pop_context` arise while
+    /// parsing `context`, e.g. "argument 2 of call to 'f'".
+    pub fn push_context(&mut self, context: String) {
+        self.context.push(context);
+    }
+
+    pub fn pop_context(&mut self) {
+        self.context.pop();
     }
 
     fn step_chr(&mut self) {
-        self.pos += 1;
-        self.col += 1;
+        if let Some(ch) = self.cur_char {
+            self.pos += ch.len_utf8();
+        }
         self.cur_char = self.chars.next();
-        if matches!(self.cur_char, Some(ch) if ch == '\n') {
-            self.line += 1;
-            self.col = 0;
+    }
+
+    fn lex_token(&mut self) -> Result<Option<Token>, LexError> {
+        let before = self.pos;
+        self.skip_trivia()?;
+        self.token_start = self.pos;
+
+        let at_line_end = self.cur_char.is_none() || self.source.text()[before..self.pos].contains(['\n', '\r']);
+        if self.options.newline_separators && self.ends_stmt && at_line_end {
+            self.ends_stmt = false;
+            self.used_newline_separators = true;
+            return Ok(Some(Token::Semi));
         }
+
+        let tok = self.lex_raw_token()?;
+        self.ends_stmt = !self.after_cfg_if && tok.as_ref().is_some_and(Token::ends_statement);
+        self.after_cfg_if = tok == Some(Token::CfgIf);
+        Ok(tok)
     }
 
-    fn lex_token(&mut self) -> Option<Token> {
-        self.skip_whitespace();
-        match self.cur_char {
+    fn lex_raw_token(&mut self) -> Result<Option<Token>, LexError> {
+        let tok = match self.cur_char {
             Some('+') => {
                 self.step_chr();
                 Some(Token::Plus)
@@ -172,6 +764,14 @@ impl<'a> Lexer<'a> {
                 self.step_chr();
                 Some(Token::Div)
             }
+            Some('<') => {
+                self.step_chr();
+                Some(Token::Lt)
+            }
+            Some('>') => {
+                self.step_chr();
+                Some(Token::Gt)
+            }
             Some('(') => {
                 self.step_chr();
                 Some(Token::LParen)
@@ -208,59 +808,111 @@ impl<'a> Lexer<'a> {
                 self.step_chr();
                 Some(Token::Semi)
             }
-            Some('"') => Some(self.lex_string()),
-            Some(ch) if ch.is_digit(10) => Some(self.lex_number()),
-            Some(ch) if ch.is_alphabetic() || ch == '_' => Some(self.lex_ident()),
+            Some('@') => Some(self.lex_directive()?),
+            Some('"') => Some(self.lex_string()?),
+            Some(ch) if ch.is_ascii_digit() => Some(self.lex_number()?),
+            Some(ch) if ch.is_ascii_alphabetic() || ch == '_' => Some(self.lex_ident()),
             Some(ch) => {
-                println!("Invalid character '{}' on line {} column {}", ch, self.line, self.col);
-                self.point_error();
+                let span = Span { start: self.pos, end: self.pos + ch.len_utf8() };
+                return Err(match (confusable(ch), invisible(ch)) {
+                    (Some((name, ascii)), _) => {
+                        let mut err = self.lex_error(span, format!("Invalid character U+{:04X} {}", ch as u32, name));
+                        err.notes.push(format!("did you mean '{}'?", ascii));
+                        err
+                    }
+                    (_, Some(name)) => {
+                        let mut err = self.lex_error(span, format!("Invisible character U+{:04X} {}", ch as u32, name));
+                        err.notes.push("remove it; --fix-confusables does this for you".to_owned());
+                        err
+                    }
+                    _ => self.lex_error(span, format!("Invalid character '{}'", ch)),
+                });
             }
             None => None,
+        };
+        Ok(tok)
+    }
+
+    fn lex_error(&self, span: Span, message: String) -> LexError {
+        LexError::new(&self.source, span, message)
+    }
+
+    fn lex_directive(&mut self) -> Result<Token, LexError> {
+        let start = self.pos;
+        self.consume_char('@');
+        let name_start = self.pos;
+        self.consume_while(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        let name = &self.source.text()[name_start..self.pos];
+        match DIRECTIVES.iter().find(|(directive, _)| *directive == name) {
+            Some((_, tok)) => Ok(tok.clone()),
+            None => Err(self.lex_error(Span { start, end: self.pos }, format!("Unknown directive '@{}'", name))),
         }
     }
 
-    fn lex_string(&mut self) -> Token {
-        Token::String("".to_owned());
-        let mut ret = String::new();
+    /// Lexes a string literal. Adjacent literals such as `"a" "b"` are separate
+    /// tokens; there is no implicit concatenation.
+    fn lex_string(&mut self) -> Result<Token, LexError> {
+        let open = self.pos;
         self.consume_char('"');
+        let start = self.pos;
+        self.consume_while(|c| c != '\\' && c != '"');
 
+        // Literals without escapes are copied out of the source in one go.
+        if self.consume_char('"') {
+            return Ok(Token::String(self.source.text()[start..self.pos - 1].to_owned()));
+        }
+
+        let mut ret = self.source.text()[start..self.pos].to_owned();
         loop {
-            let start = self.pos;
-            self.consume_while(|c| c != '\\' && c != '"');
-
-            ret.push_str(&self.input[start..self.pos]);
-            if self.consume_char('\\') {
-                match self.cur_char {
-                    Some('n') => ret.push('\n'),
-                    Some('t') => ret.push('\t'),
-                    Some('"') => ret.push('"'),
-                    Some(c) => {
-                        println!("Invalid escape sequence '\\{}' on line {} column {}", c, self.line, self.col);
-                        self.point_error();
-                    },
-                    None => {
-                        println!("Unexpected end of input while parsing string on line {} column {}", self.line, self.col);
-                        self.point_error();
-                    },
+            match self.cur_char {
+                Some('"') => {
+                    self.step_chr();
+                    break;
                 }
-                self.step_chr();
-            } else if self.consume_char('"') {
-                break
+                Some('\\') => {
+                    self.step_chr();
+                    match self.cur_char {
+                        Some('n') => ret.push('\n'),
+                        Some('t') => ret.push('\t'),
+                        Some('"') => ret.push('"'),
+                        Some('\\') => ret.push('\\'),
+                        Some(c) => {
+                            let span = Span { start: self.pos - 1, end: self.pos + c.len_utf8() };
+                            return Err(self.lex_error(span, format!("Invalid escape sequence '\\{}'", c)));
+                        }
+                        None => return Err(self.unterminated_string(open)),
+                    }
+                    self.step_chr();
+                }
+                Some(_) => {
+                    let segment = self.pos;
+                    self.consume_while(|c| c != '\\' && c != '"');
+                    ret.push_str(&self.source.text()[segment..self.pos]);
+                }
+                None => return Err(self.unterminated_string(open)),
             }
         }
 
-        Token::String(ret)
+        Ok(Token::String(ret))
+    }
+
+    fn unterminated_string(&self, open: usize) -> LexError {
+        self.lex_error(Span { start: open, end: self.pos }, "Unterminated string starting".to_owned())
     }
 
-    fn lex_number(&mut self) -> Token {
+    fn lex_number(&mut self) -> Result<Token, LexError> {
         let start = self.pos;
         self.consume_digits();
         
+        let text = &self.source.text()[start..self.pos];
         if self.consume_char('.') {
             self.consume_digits();
-            Token::Float(self.input[start..self.pos].parse().unwrap())
+            Ok(Token::Float(self.source.text()[start..self.pos].parse().unwrap()))
         } else {
-            Token::Int(self.input[start..self.pos].parse().unwrap())
+            text.parse().map(Token::Int).map_err(|_| {
+                self.lex_error(Span { start, end: self.pos }, "Integer literal doesn't fit in 64 bits".to_owned())
+            })
         }
     }
 
@@ -268,11 +920,25 @@ impl<'a> Lexer<'a> {
         let start = self.pos;
 
         self.consume_while(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_'));
-        let ret = &self.input[start..self.pos];
+        let ret = &self.source.text()[start..self.pos];
 
         match KEYWORDS.get(ret) {
             Some(tok) => tok.clone(),
-            None => Token::Identifier(ret.to_owned()),
+            None if self.options.case_insensitive_keywords => match KEYWORDS.get(ret.to_ascii_lowercase().as_str()) {
+                Some(tok) => {
+                    self.used_case_insensitive_keywords = true;
+                    tok.clone()
+                }
+                None => self.lex_extra_keyword(ret),
+            },
+            None => self.lex_extra_keyword(ret),
+        }
+    }
+
+    fn lex_extra_keyword(&self, ident: &str) -> Token {
+        match self.options.extra_keywords.iter().find(|(keyword, _)| keyword == ident) {
+            Some(&(_, id)) => Token::Custom(id),
+            None => Token::Identifier(ident.to_owned()),
         }
     }
 
@@ -294,11 +960,49 @@ impl<'a> Lexer<'a> {
     }
 
     fn consume_digits(&mut self) {
-        self.consume_while(|c| c.is_digit(10))
+        self.consume_while(|c| c.is_ascii_digit())
     }
 
     fn skip_whitespace(&mut self) {
         self.consume_while(|c| c.is_whitespace())
     }
-}
 
+    /// Skips whitespace and the comments in `COMMENTS`. A line comment ends at
+    /// any line terminator `SourceFile` knows.
+    fn skip_trivia(&mut self) -> Result<(), LexError> {
+        loop {
+            self.skip_whitespace();
+            let rest = &self.source.text()[self.pos..];
+            match COMMENTS.iter().find(|(open, _)| rest.starts_with(open)) {
+                Some((_, None)) => self.consume_while(|c| c != '\n' && c != '\r'),
+                Some(&(open, Some(close))) => self.skip_block_comment(open, close)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn skip_block_comment(&mut self, open: &str, close: &str) -> Result<(), LexError> {
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            let rest = &self.source.text()[self.pos..];
+            let delimiter = if rest.starts_with(open) {
+                depth += 1;
+                open
+            } else if rest.starts_with(close) {
+                depth -= 1;
+                close
+            } else if rest.is_empty() {
+                let span = Span { start, end: start + open.len() };
+                return Err(self.lex_error(span, "Unterminated block comment starting".to_owned()));
+            } else {
+                self.step_chr();
+                continue;
+            };
+            delimiter.chars().for_each(|_| self.step_chr());
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,11 @@
+pub mod lexer;
+pub mod parser;
+pub mod compiler;
+pub mod error;
+pub mod source;
+pub mod stats;
+pub mod syntax;
+pub mod vm;
+
+pub use compiler::{CompileOptions, Compiler};
+pub use parser::{Expr, Stmt};
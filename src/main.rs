@@ -1,33 +1,620 @@
-mod lexer;
-mod parser;
-mod compiler;
+use std::{collections::HashMap, env, fs, io::{self, BufReader, Read, Write}, path::{Path, PathBuf}, process, time::{Duration, Instant}};
+use parse::{
+    compiler::{self, CompileOptions, CompilePhases, Compiler, Limit, Limits, SizeReport, SpannedCode},
+    error::ParseError,
+    lexer::{self, Lexer, LexerOptions},
+    parser::{self, Parser, ParserOptions, Stmt},
+    source::SourceFile,
+    stats::Stats,
+    syntax,
+    vm::{BbqMode, Vm, VmOptions},
+};
 
-use std::{env, fs};
-use lexer::Lexer;
-use parser::Parser;
-use compiler::Compiler;
 
+/// Bundled example programs in `examples/`, one `name<TAB>description` per line.
+const EXAMPLES: &str = include_str!("../examples/manifest.txt");
+
+/// A loaded source file, named relative to the path given on the command line.
+struct Source {
+    name: String,
+    /// Where the file lives on disk, or `None` for a buffer read from stdin.
+    path: Option<PathBuf>,
+    text: String,
+}
+
+/// Phase and completion reporting for `--progress`, measured from startup.
+struct Progress {
+    enabled: bool,
+    start: Instant,
+    percent: usize,
+}
+
+impl Progress {
+    fn phase(&mut self, name: &str) {
+        self.percent = 0;
+        if self.enabled {
+            println!("[{:>8.3}s] {}", self.start.elapsed().as_secs_f64(), name);
+        }
+    }
+
+    /// Reports `done` out of `total` bytes processed, in steps of 10%.
+    fn advance(&mut self, name: &str, done: usize, total: usize) {
+        if !self.enabled || total == 0 {
+            return;
+        }
+        let percent = done * 10 / total * 10;
+        if percent > self.percent {
+            self.percent = percent;
+            println!("[{:>8.3}s] {} {}%", self.start.elapsed().as_secs_f64(), name, percent);
+        }
+    }
+}
+
+/// Wall time and item counts per phase for `--timings`, printed in phase
+/// order once compilation finishes.
+#[derive(Default)]
+struct Timings {
+    enabled: bool,
+    phases: Vec<(String, Duration, String)>,
+}
+
+impl Timings {
+    fn record(&mut self, name: &str, start: Instant, items: String) {
+        self.record_time(name, start.elapsed(), items);
+    }
+
+    fn record_time(&mut self, name: &str, time: Duration, items: String) {
+        self.phases.push((name.to_owned(), time, items));
+    }
+
+    fn print(&self) {
+        if !self.enabled {
+            return;
+        }
+        println!("Timings:");
+        for (name, time, items) in &self.phases {
+            println!("    {:<16} {:>9.3}ms  {}", name, time.as_secs_f64() * 1000.0, items);
+        }
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
+    let start = Instant::now();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "dump-isa") {
+        if args.iter().any(|arg| arg == "--json") {
+            print!("{}", compiler::isa_json());
+        } else {
+            print!("{}", compiler::isa_markdown());
+        }
+        return;
+    }
+    if args.first().is_some_and(|arg| arg == "dump-grammar") {
+        print!("{}", parser::operators_markdown());
+        return;
+    }
+    if args.first().is_some_and(|arg| arg == "dump-syntax") {
+        let format = match args.iter().position(|arg| arg == "--format") {
+            Some(i) => option_value("--format", args.get(i + 1)),
+            None => "json",
+        };
+        match format {
+            "json" => print!("{}", syntax::syntax_json()),
+            "textmate" => print!("{}", syntax::textmate_grammar()),
+            "vim" => print!("{}", syntax::vim_syntax()),
+            format => {
+                println!("Unknown syntax format {}", format);
+                process::exit(1)
+            }
+        }
         return;
     }
+    if args.first().is_some_and(|arg| arg == "stats") {
+        let path = option_value("stats", args.iter().skip(1).find(|arg| !arg.starts_with("--")));
+        let text = read_source(Path::new(path), &mut ByteBudget { limits: &Limits::default(), read: 0 });
+        let stats = Stats::collect(&text);
+        if args.iter().any(|arg| arg == "--json") {
+            print!("{}", stats.to_json());
+        } else {
+            print!("{}", stats);
+            let source = SourceFile::named(path, &text);
+            stats.errors.iter().for_each(|err| print!("{}", err.report(&source, &LexerOptions::default())));
+        }
+        if !stats.errors.is_empty() {
+            process::exit(1)
+        }
+        return;
+    }
+    if args.first().is_some_and(|arg| arg == "examples") {
+        for (name, description) in EXAMPLES.lines().filter_map(|line| line.split_once('\t')) {
+            println!("{:<16} {}", name, description);
+        }
+        return;
+    }
+
+    let mut all = false;
+    let mut report_size = false;
+    let mut annotated = false;
+    let mut run = false;
+    let mut pedantic_stack = false;
+    let mut fix = false;
+    let mut progress = Progress { enabled: false, start, percent: 0 };
+    let mut timings = Timings::default();
+    let mut lexer_options = LexerOptions::default();
+    let mut parser_options = ParserOptions::default();
+    let mut options = CompileOptions::default();
+    let mut vm_options = VmOptions::default();
+    let mut paths = Vec::new();
+    let mut stdin_name = None;
+    let mut deny_todo = false;
+    let mut preludes = Vec::new();
+    let mut default_prelude = true;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--all" => all = true,
+            "--report-size" => report_size = true,
+            "--run" => run = true,
+            "--emit" => match option_value(arg, args.next()) {
+                "annotated" => annotated = true,
+                "bytecode" => annotated = false,
+                emit => {
+                    println!("Unknown output kind {}", emit);
+                    process::exit(1)
+                }
+            },
+            "--full-lines" => lexer_options.full_lines = true,
+            "--tab-width" => match option_value(arg, args.next()).parse() {
+                Ok(width) => lexer_options.tab_width = width,
+                Err(_) => {
+                    println!("Option {} needs a number", arg);
+                    process::exit(1)
+                }
+            },
+            "--bbq-mode" => match option_value(arg, args.next()) {
+                "unicode" => vm_options.bbq_mode = BbqMode::Unicode,
+                "byte" => vm_options.bbq_mode = BbqMode::Byte,
+                "chicken-compat" => vm_options.bbq_mode = BbqMode::ChickenCompat,
+                mode => {
+                    println!("Unknown bbq mode {}", mode);
+                    process::exit(1)
+                }
+            },
+            "--pedantic-stack" => pedantic_stack = true,
+            "--fix-confusables" => fix = true,
+            "--calc" => parser_options.implicit_push = true,
+            "--char-literal-coercion" => parser_options.char_literal_coercion = true,
+            "--progress" => progress.enabled = true,
+            "--timings" => timings.enabled = true,
+            "--limits" => {
+                print!("{}", options.limits);
+                return;
+            }
+            "--limit" => {
+                let (limit, max) = parse_limit(option_value(arg, args.next())).unwrap_or_else(|| {
+                    println!("Option {} needs a value like tokens=1000", arg);
+                    process::exit(1)
+                });
+                options.limits.set(limit, max);
+            }
+            "--compat" => match option_value(arg, args.next()) {
+                "eggsembly-classic" => {
+                    lexer_options = lexer_options.classic(true);
+                    parser_options.char_literal_coercion = true;
+                }
+                mode => {
+                    println!("Unknown compatibility mode {}", mode);
+                    process::exit(1)
+                }
+            },
+            "--prelude" => preludes.push(PathBuf::from(option_value(arg, args.next()))),
+            "--no-default-prelude" => default_prelude = false,
+            "-Ochicken-size" => options.chicken_size_budget = Some(10_000),
+            "--chicken-size-budget" => match option_value(arg, args.next()).parse() {
+                Ok(budget) => options.chicken_size_budget = Some(budget),
+                Err(_) => {
+                    println!("Option {} needs a number", arg);
+                    process::exit(1)
+                }
+            },
+            "--deny" => match option_value(arg, args.next()) {
+                "todo" => deny_todo = true,
+                lint => {
+                    println!("Unknown lint {}", lint);
+                    process::exit(1)
+                }
+            },
+            "--stdin-filename" => stdin_name = Some(option_value(arg, args.next())),
+            "--define" => options.defines.push(option_value(arg, args.next()).to_owned()),
+            _ if arg.starts_with("--") => {
+                println!("Unknown option {}", arg);
+                process::exit(1)
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != usize::from(stdin_name.is_none()) {
+        return;
+    }
+
+    let limits = options.limits.clone();
+    progress.phase("read");
+    if default_prelude {
+        if let Some(paths) = env::var_os("EGGSEMBLY_PRELUDE") {
+            preludes.splice(0..0, env::split_paths(&paths));
+        }
+    }
+    let mut budget = ByteBudget { limits: &limits, read: 0 };
+    let mut sources: Vec<_> = preludes.iter().flat_map(|path| load_sources(path, false, &mut budget)).collect();
+    match stdin_name {
+        Some(name) => sources.push(read_stdin(name, &mut budget)),
+        None => sources.extend(load_sources(Path::new(paths[0]), all, &mut budget)),
+    }
+    let total_bytes = budget.read;
+    if fix {
+        sources.iter_mut().for_each(fix_confusables);
+    }
 
-    let input = fs::read_to_string(args[1].to_owned()).unwrap();
+    progress.phase("lex");
+    let phase = Instant::now();
+    let mut token_count = 0;
+    let mut done_bytes = 0;
+    let mut lexers = Vec::new();
+    for source in &sources {
+        let mut lexer = Lexer::with_options(SourceFile::named(&source.name, &source.text), lexer_options.clone());
+        let mut toks = Vec::new();
+        lexer.buffer(|tok, span| {
+            toks.push(tok.clone());
+            token_count += 1;
+            check_limit(&limits, Limit::Tokens, token_count);
+            progress.advance("lex", done_bytes + span.end, total_bytes);
+        });
+        if let Some(err) = lexer.take_error() {
+            fail(&err, lexer.source(), &lexer_options)
+        }
+        done_bytes += source.text.len();
+        progress.advance("lex", done_bytes, total_bytes);
+        println!("Tokens ({}): {:?}", source.name, toks);
+        lexers.push(lexer);
+    }
 
-    let mut lexer = Lexer::new(&input);
-    let toks: Vec<_> = lexer.into_iter().collect();
-    println!("Tokens: {:?}", toks);
+    timings.record("lex", phase, format!("{} tokens", token_count));
 
-    let mut lexer = Lexer::new(&input);
-    let parser = Parser::new(&mut lexer);
-    let ast = parser.parse();
+    progress.phase("parse");
+    let phase = Instant::now();
+    let mut stmts = Vec::new();
+    let mut stmt_starts = Vec::new();
+    let mut classic_used = Vec::new();
+    let mut todos = Vec::new();
+    let mut done_bytes = 0;
+    for (index, (source, mut lexer)) in sources.iter().zip(lexers).enumerate() {
+        if let Err(err) = lexer.check_brackets() {
+            fail(&err, lexer.source(), &lexer_options)
+        }
+        let mut parser = Parser::with_options(lexer, parser_options.clone());
+        let mut failed = false;
+        loop {
+            let start = parser.lexer().token_start();
+            let stmt = match parser.next() {
+                Some(Ok(stmt)) => stmt,
+                Some(Err(err)) => {
+                    print!("{}", err.report(parser.lexer().source(), &lexer_options));
+                    failed = true;
+                    continue;
+                }
+                None if failed => process::exit(1),
+                None => break,
+            };
+            stmts.push(stmt);
+            stmt_starts.push((index, start));
+            progress.advance("parse", done_bytes + parser.lexer().token_start(), total_bytes);
+        }
+        done_bytes += source.text.len();
+        for note in parser.notes() {
+            println!("note: {}", note);
+        }
+        todos.extend(parser.todos().iter().map(|todo| (&source.name, todo.clone())));
+        for behavior in parser.lexer().classic_behaviors_used() {
+            if !classic_used.contains(&behavior) {
+                classic_used.push(behavior);
+            }
+        }
+    }
+    for (name, todo) in &todos {
+        println!(
+            "{}: not yet implemented{} at {}:{}:{}",
+            if deny_todo { "error" } else { "warning" },
+            todo.message.as_ref().map_or(String::new(), |message| format!(": {}", message)),
+            name,
+            todo.line,
+            todo.col
+        );
+    }
+    if deny_todo && !todos.is_empty() {
+        process::exit(1)
+    }
+    if !classic_used.is_empty() {
+        println!("note: compiled using classic Eggsembly {}", classic_used.join(" and "));
+    }
+    check_duplicate_builds(&sources, &stmts, &stmt_starts);
+    let node_count = stmts.iter().map(Stmt::node_count).sum();
+    timings.record("parse", phase, format!("{} nodes", node_count));
+    check_limit(&limits, Limit::AstNodes, node_count);
+    if pedantic_stack {
+        if let Err(err) = compiler::check_stack_balance(&stmts, &options) {
+            println!("{}", err);
+            process::exit(1)
+        }
+    }
+    let mut ast = Stmt::StmtSeq(stmts);
     println!("AST:\n{:#?}\n", ast);
 
-    let compiler = Compiler::new();
-    let code = compiler.compile(&ast);
-    println!("Bytecode: {:?}", code);
+    progress.phase("compile");
+    let phase = Instant::now();
+    let compiler = Compiler::with_options(options.clone());
+    let mut phases = CompilePhases::default();
+    let code = compiler.compile_with_phases(&mut ast, &mut phases).unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(1)
+    });
+    for pass in phases.timings() {
+        timings.record_time(&format!("pass {}", pass.name), pass.time, format!("{} nodes", pass.nodes));
+    }
+    timings.record("compile", phase, format!("{} instructions", code.len()));
+    check_limit(&limits, Limit::Instructions, code.len());
+    progress.phase("write");
+    if report_size {
+        let Stmt::StmtSeq(stmts) = &ast else { unreachable!() };
+        let (code, origins, _) = compile_with_spans(stmts, &options);
+        let files: Vec<_> = sources.iter().map(|source| SourceFile::named(&source.name, &source.text)).collect();
+        let lines: Vec<_> = stmt_starts
+            .iter()
+            .map(|&(index, offset)| (sources[index].name.as_str(), files[index].line_col(offset).0))
+            .collect();
+        print!("{}", SizeReport::with_origins(&code, &origins, stmts, &lines));
+    } else if annotated {
+        let Stmt::StmtSeq(stmts) = &ast else { unreachable!() };
+        print_annotated(&sources, stmts, &stmt_starts, &options);
+    } else if run {
+        let mut vm = Vm::with_options(code, vm_options);
+        if let Err(err) = vm.run() {
+            println!("Runtime error: {}", err);
+            process::exit(1)
+        }
+        if vm.output().is_some() {
+            let mut out = io::stdout().lock();
+            vm.write_output(&mut out).and_then(|()| writeln!(out)).expect("stdout is writable");
+        }
+    } else {
+        println!("Bytecode: {:?}", code);
+    }
+    progress.phase("done");
+    timings.print();
+}
+
+/// Compiles the statements one `append` at a time, so each instruction's
+/// origin is the index of its statement. They must already have compiled as a
+/// whole.
+fn compile_with_spans(stmts: &[Stmt], options: &CompileOptions) -> SpannedCode {
+    let mut compiler = Compiler::with_options(options.clone());
+    for stmt in stmts {
+        compiler.append(stmt).expect("program compiled above");
+    }
+    compiler.finish_with_spans().expect("program compiled above")
+}
+
+/// Prints each top-level statement's first source line once, followed by the
+/// instructions the statements starting on it compile to. An instruction
+/// compiled from an operator or call also echoes that expression's source.
+/// `starts` holds the source index and byte offset where each statement
+/// begins. The statements must already have compiled as a whole.
+fn print_annotated(sources: &[Source], stmts: &[Stmt], starts: &[(usize, usize)], options: &CompileOptions) {
+    let files: Vec<_> = sources.iter().map(|source| SourceFile::named(&source.name, &source.text)).collect();
+    let (code, origins, spans) = compile_with_spans(stmts, options);
+    let mut shown = None;
+    for (number, &(index, offset)) in starts.iter().enumerate() {
+        let (line, _) = files[index].line_col(offset);
+        if shown != Some((index, line)) {
+            shown = Some((index, line));
+            println!("{}:{}: {}", sources[index].name, line, files[index].line_text(line).trim());
+        }
+        for (address, code) in code.iter().enumerate().filter(|&(address, _)| origins[address] == Some(number)) {
+            match spans[address] {
+                Some(expr) => {
+                    let text = &sources[index].text[expr.span.start..expr.span.end];
+                    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    println!("    {:>4}  {:<16} ; from '{}' ({})", address, format!("{:?}", code), text, expr.what);
+                }
+                None => println!("    {:>4}  {:?}", address, code),
+            }
+        }
+    }
+    if origins.contains(&None) {
+        println!("(end of program)");
+        for (address, code) in code.iter().enumerate().filter(|&(address, _)| origins[address].is_none()) {
+            println!("    {:>4}  {:?}", address, code);
+        }
+    }
+}
+
+/// Prints an error with the offending source line and exits.
+fn fail(err: &ParseError, source: &SourceFile, options: &LexerOptions) -> ! {
+    print!("{}", err.report(source, options));
+    process::exit(1)
+}
+
+/// Rejects a build defined twice across the loaded files, naming the file of
+/// each definition, unless the later one is an `override build`. The compiler
+/// catches duplicates too, but it only knows line numbers, and a prelude and
+/// the main file both have a line 1.
+fn check_duplicate_builds(sources: &[Source], stmts: &[Stmt], starts: &[(usize, usize)]) {
+    let mut defined = HashMap::new();
+    for (stmt, &(index, _)) in stmts.iter().zip(starts) {
+        let Stmt::FuncDef { name, overrides, line, col, .. } = stmt else { continue };
+        let here = format!("{}:{}:{}", sources[index].name, line, col);
+        if let Some(first) = defined.insert(name, here.clone()).filter(|_| !overrides) {
+            println!("Build '{}' is defined twice, at {} and at {}", name, first, here);
+            process::exit(1)
+        }
+    }
+}
+
+fn check_limit(limits: &Limits, limit: Limit, found: usize) {
+    if let Err(err) = limits.check(limit, found) {
+        println!("{}", err);
+        process::exit(1)
+    }
+}
+
+/// Parses `name=max`, spelling the limit's name with dashes, such as
+/// `ast-nodes=500`.
+fn parse_limit(value: &str) -> Option<(Limit, usize)> {
+    let (name, max) = value.split_once('=')?;
+    let limit = Limit::ALL.into_iter().find(|limit| limit.name().replace(' ', "-").eq_ignore_ascii_case(name))?;
+    Some((limit, max.parse().ok()?))
+}
+
+fn option_value<'a>(option: &str, value: Option<&'a String>) -> &'a str {
+    match value {
+        Some(value) => value,
+        None => {
+            println!("Option {} needs a value", option);
+            process::exit(1)
+        }
+    }
+}
+
+/// Rewrites confusable characters in a source file to ASCII and removes
+/// invisible ones, in memory and on disk unless it came from stdin, reporting
+/// each change.
+fn fix_confusables(source: &mut Source) {
+    let (fixed, fixes) = lexer::fix_confusables(&source.text);
+    if fixes.is_empty() {
+        return;
+    }
+
+    let file = SourceFile::new(&source.text);
+    for fix in &fixes {
+        let (line, col) = file.line_col(fix.offset);
+        match fix.replacement {
+            Some(replacement) => println!(
+                "{}:{}:{}: replaced U+{:04X} {} with '{}'",
+                source.name, line, col, fix.found as u32, fix.name, replacement
+            ),
+            None => println!("{}:{}:{}: removed U+{:04X} {}", source.name, line, col, fix.found as u32, fix.name),
+        }
+    }
+    if let Some(path) = &source.path {
+        if let Err(err) = fs::write(path, &fixed) {
+            println!("Could not write {}: {}", path.display(), err);
+            process::exit(1)
+        }
+    }
+    source.text = fixed;
+}
+
+/// Loads the program rooted at `path`. A directory loads its `main.egg`, or
+/// with `all` every `*.egg` file in it in sorted order.
+fn load_sources(path: &Path, all: bool, budget: &mut ByteBudget) -> Vec<Source> {
+    if !path.is_dir() {
+        return vec![Source {
+            name: path.display().to_string(),
+            path: Some(path.to_owned()),
+            text: read_source(path, budget),
+        }];
+    }
+
+    let mut files: Vec<_> = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext == "egg"))
+            .collect(),
+        Err(err) => {
+            println!("Could not read directory {}: {}", path.display(), err);
+            process::exit(1)
+        }
+    };
+    files.sort();
+
+    if !all {
+        let main = path.join("main.egg");
+        if !files.contains(&main) {
+            println!("No main.egg found in {}", path.display());
+            if files.is_empty() {
+                println!("The directory contains no .egg files");
+            } else {
+                println!("Found these .egg files (use --all to compile them all):");
+                for file in &files {
+                    println!("    {}", relative_name(path, file));
+                }
+            }
+            process::exit(1)
+        }
+        files = vec![main];
+    }
+
+    files
+        .iter()
+        .map(|file| Source {
+            name: relative_name(path, file),
+            path: Some(file.to_owned()),
+            text: read_source(file, budget),
+        })
+        .collect()
 }
 
+fn relative_name(dir: &Path, file: &Path) -> String {
+    file.strip_prefix(dir).unwrap_or(file).display().to_string()
+}
+
+/// Reads the program from stdin, labelling its diagnostics with `name` as if
+/// it had been read from that path, for editors checking unsaved buffers.
+fn read_stdin(name: &str, budget: &mut ByteBudget) -> Source {
+    match budget.read_to_string(io::stdin().lock(), 0) {
+        Ok(text) => Source { name: name.to_owned(), path: None, text },
+        Err(err) => {
+            println!("Could not read stdin: {}", err);
+            process::exit(1)
+        }
+    }
+}
 
+fn read_source(path: &Path, budget: &mut ByteBudget) -> String {
+    let read = |path: &Path, budget: &mut ByteBudget| {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        budget.read_to_string(BufReader::new(file), len)
+    };
+    match read(path, budget) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Could not read {}: {}", path.display(), err);
+            process::exit(1)
+        }
+    }
+}
+
+/// The source bytes read so far, held to `Limit::SourceBytes` before and
+/// while each input is read, so an oversized one is never buffered whole.
+struct ByteBudget<'l> {
+    limits: &'l Limits,
+    read: usize,
+}
+
+impl ByteBudget<'_> {
+    /// Reads all of `input`, whose length is `expected` if known and 0 if
+    /// not. Exits at once if `expected` is over the limit, and otherwise
+    /// once a byte past the limit has been read.
+    fn read_to_string(&mut self, input: impl Read, expected: u64) -> io::Result<String> {
+        let max = self.limits.source_bytes;
+        let expected = usize::try_from(expected).unwrap_or(usize::MAX);
+        check_limit(self.limits, Limit::SourceBytes, self.read.saturating_add(expected));
+        let mut bytes = Vec::with_capacity(expected);
+        input.take((max - self.read) as u64 + 1).read_to_end(&mut bytes)?;
+        self.read += bytes.len();
+        check_limit(self.limits, Limit::SourceBytes, self.read);
+        String::from_utf8(bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))
+    }
+}
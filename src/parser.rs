@@ -1,6 +1,13 @@
-use crate::lexer::{Lexer, Token};
+use std::ops;
 
-#[derive(Debug, Clone)]
+use crate::{
+    error::ParseError,
+    lexer::{CustomKeywordId, Lexer, Token, TokenKind},
+    source::Span,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Stmt {
     StmtSeq(Vec<Stmt>),
     Axe,
@@ -9,230 +16,902 @@ pub enum Stmt {
     Fox,
     Rooster,
     Cmp,
-    Pick,
-    Peck,
+    Pick(Option<u32>),
+    Peck(Option<u32>),
     Fr,
     Bbq,
     Push(Expr),
-    #[allow(dead_code)]
-    Ass(String, Expr)
+    /// `todo;` or `todo "message";`: a hole that compiles but stops the
+    /// program if reached.
+    Todo(Option<String>),
+    /// `@if flag ... @end`: statements compiled only when `flag` is defined.
+    CfgBlock {
+        flag: String,
+        body: Vec<Stmt>,
+    },
+    /// A statement introduced by an embedder's keyword. Its operands are
+    /// pushed in order before the embedder's lowering runs.
+    Custom {
+        id: CustomKeywordId,
+        args: Vec<Expr>,
+        /// Where the keyword appears, filled in by the parser once the
+        /// embedder's hook returns; 0 for nodes built in code.
+        line: usize,
+        col: usize,
+    },
+    /// `let name = expr;`, storing the value in the variable's slot.
+    Ass(String, Expr),
+    /// `compare { then } fr { otherwise }`: pops two values and runs `then`
+    /// if they are equal, else `otherwise`, which is empty without the `fr`.
+    CompareIf {
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+    },
+    /// `build name(params) { body }`. Calling it runs `body` with the
+    /// arguments bound to `params`; whatever the body leaves on the stack is
+    /// the result.
+    FuncDef {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Stmt>,
+        /// Written `override build`: replaces an earlier build of the same
+        /// name, everywhere it is hatched, instead of being an error.
+        overrides: bool,
+        /// Where the definition starts; 0 for nodes built in code.
+        line: usize,
+        col: usize,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Int(i64),
     Float(f64),
+    String(String),
+    /// An operator and its operands. `span` covers the whole expression in
+    /// the source, so annotated listings can echo it; empty for nodes built
+    /// in code.
     BinOp {
         op: Token,
         left: Box<Expr>,
         right: Box<Expr>,
+        span: Span,
     },
     UnOp {
         op: Token,
         operand: Box<Expr>,
+        span: Span,
     },
+    /// A call, with where it appears for undefined-function and arity errors;
+    /// 0 for nodes built in code. `span` runs from the name to the `)`.
     FunctionCall {
         name: String,
         args: Vec<Expr>,
+        line: usize,
+        col: usize,
+        span: Span,
     },
-    Variable(String),
+    /// A variable read, with where it appears for undefined-variable errors;
+    /// 0 for nodes built in code.
+    Variable {
+        name: String,
+        line: usize,
+        col: usize,
+    },
+}
+
+impl Stmt {
+    /// Number of statement and expression nodes in this tree.
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            Stmt::StmtSeq(seq) | Stmt::CfgBlock { body: seq, .. } | Stmt::FuncDef { body: seq, .. } => {
+                seq.iter().map(Stmt::node_count).sum()
+            }
+            Stmt::CompareIf { then, otherwise } => then.iter().chain(otherwise).map(Stmt::node_count).sum(),
+            Stmt::Push(expr) | Stmt::Ass(_, expr) => expr.node_count(),
+            Stmt::Custom { args, .. } => args.iter().map(Expr::node_count).sum(),
+            _ => 0,
+        }
+    }
+}
+
+/// Constructors for building programs in code instead of parsing text, e.g.
+/// `Stmt::push(Expr::int(5) * Expr::var("x"))`. Nodes built this way have no
+/// source position, so diagnostics about them say "in generated code".
+impl Stmt {
+    pub fn seq(stmts: Vec<Stmt>) -> Stmt {
+        Stmt::StmtSeq(stmts)
+    }
+
+    pub fn push(expr: Expr) -> Stmt {
+        Stmt::Push(expr)
+    }
+
+    pub fn assign(name: &str, expr: Expr) -> Stmt {
+        Stmt::Ass(name.to_owned(), expr)
+    }
+
+    pub fn compare_if(then: Vec<Stmt>, otherwise: Vec<Stmt>) -> Stmt {
+        Stmt::CompareIf { then, otherwise }
+    }
+
+    pub fn func(name: &str, params: &[&str], body: Vec<Stmt>) -> Stmt {
+        Stmt::FuncDef {
+            name: name.to_owned(),
+            params: params.iter().map(|&param| param.to_owned()).collect(),
+            body,
+            overrides: false,
+            line: 0,
+            col: 0,
+        }
+    }
+}
+
+impl Expr {
+    pub fn int(num: i64) -> Expr {
+        Expr::Int(num)
+    }
+
+    pub fn float(num: f64) -> Expr {
+        Expr::Float(num)
+    }
+
+    pub fn string(text: &str) -> Expr {
+        Expr::String(text.to_owned())
+    }
+
+    pub fn var(name: &str) -> Expr {
+        Expr::Variable { name: name.to_owned(), line: 0, col: 0 }
+    }
+
+    pub fn call(name: &str, args: Vec<Expr>) -> Expr {
+        Expr::FunctionCall { name: name.to_owned(), args, line: 0, col: 0, span: Span::default() }
+    }
+
+    /// Sets the span of an operator or call built in code, such as to compare
+    /// it with a parsed tree. Other expressions have no span and are returned
+    /// as they are.
+    pub fn spanning(mut self, start: usize, end: usize) -> Expr {
+        if let Expr::BinOp { span, .. } | Expr::UnOp { span, .. } | Expr::FunctionCall { span, .. } = &mut self {
+            *span = Span { start, end };
+        }
+        self
+    }
+
+    fn binary(self, op: Token, right: Expr) -> Expr {
+        Expr::BinOp { op, left: Box::new(self), right: Box::new(right), span: Span::default() }
+    }
+
+    pub fn lt(self, right: Expr) -> Expr {
+        self.binary(Token::Lt, right)
+    }
+
+    pub fn gt(self, right: Expr) -> Expr {
+        self.binary(Token::Gt, right)
+    }
+}
+
+macro_rules! binary_operator_impls {
+    ($($trait:ident $method:ident $token:ident),*) => {
+        $(impl ops::$trait for Expr {
+            type Output = Expr;
+
+            fn $method(self, right: Expr) -> Expr {
+                self.binary(Token::$token, right)
+            }
+        })*
+    };
+}
+
+binary_operator_impls!(Add add Plus, Sub sub Sub, Mul mul Mul, Div div Div);
+
+impl ops::Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        Expr::UnOp { op: Token::Sub, operand: Box::new(self), span: Span::default() }
+    }
+}
+
+impl Expr {
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            Expr::BinOp { left, right, .. } => left.node_count() + right.node_count(),
+            Expr::UnOp { operand, .. } => operand.node_count(),
+            Expr::FunctionCall { args, .. } => args.iter().map(Expr::node_count).sum(),
+            _ => 0,
+        }
+    }
+
+    /// Whether this expression produces a string regardless of what its
+    /// variables hold: a string literal, or `+` with one as an operand.
+    pub fn is_string(&self) -> bool {
+        match self {
+            Expr::String(_) => true,
+            Expr::BinOp { op: Token::Plus, left, right, .. } => left.is_string() || right.is_string(),
+            _ => false,
+        }
+    }
+
+    /// Folds an integer-only constant expression, returning `None` if it
+    /// references variables, calls, floats, or would overflow or divide by zero.
+    pub fn eval_const(&self) -> Option<i64> {
+        match self {
+            Expr::Int(num) => Some(*num),
+            Expr::UnOp { op: Token::Sub, operand, .. } => operand.eval_const()?.checked_neg(),
+            Expr::UnOp { op: Token::Plus, operand, .. } => operand.eval_const(),
+            Expr::BinOp { op, left, right, .. } => {
+                let (left, right) = (left.eval_const()?, right.eval_const()?);
+                match op {
+                    Token::Plus => left.checked_add(right),
+                    Token::Sub => left.checked_sub(right),
+                    Token::Mul => left.checked_mul(right),
+                    Token::Div => left.checked_div(right),
+                    Token::Lt => Some(i64::from(left < right)),
+                    Token::Gt => Some(i64::from(left > right)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Knobs controlling how statements and expressions are parsed.
+#[derive(Debug, Clone)]
+pub struct ParserOptions {
+    /// Deepest expression nesting accepted before parsing stops with an
+    /// error, so pathological input can't overflow the stack. Parentheses,
+    /// calls, unary operators and each operator in a chain like `1 + 2 + 3`
+    /// all count.
+    pub max_nesting: usize,
+    /// Deepest nesting of `{ }` and `@if` blocks accepted, for the same
+    /// reason. Each level of statements takes far more stack than a level of
+    /// expression.
+    pub max_block_nesting: usize,
+    /// A one-character string literal is accepted as its code point where a
+    /// number is expected: as an operand of any operator but `+`, or pushed
+    /// right before a `bbq`, so `push "H"; bbq;` pushes 72. Elsewhere it stays
+    /// a string.
+    pub char_literal_coercion: bool,
+    /// A bare expression statement is read as a push, so `2 + 2;` means
+    /// `push 2 + 2;`. Meant for calculator-style use, not source files.
+    pub implicit_push: bool,
+    /// Parses the rest of a statement begun by a custom keyword, which has
+    /// already been consumed. Custom keywords are errors without it.
+    pub on_custom_stmt: Option<CustomStmtParser>,
+}
+
+/// Embedder hook for custom statements; see `ParserOptions::on_custom_stmt`.
+pub type CustomStmtParser = for<'p> fn(CustomKeywordId, &mut Parser<'p>) -> Result<Stmt, ParseError>;
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            max_nesting: 256,
+            max_block_nesting: 64,
+            char_literal_coercion: false,
+            implicit_push: false,
+            on_custom_stmt: None,
+        }
+    }
+}
+
+impl ParserOptions {
+    pub fn max_nesting(mut self, max_nesting: usize) -> Self {
+        self.max_nesting = max_nesting;
+        self
+    }
+
+    pub fn max_block_nesting(mut self, max_block_nesting: usize) -> Self {
+        self.max_block_nesting = max_block_nesting;
+        self
+    }
+
+    pub fn char_literal_coercion(mut self, char_literal_coercion: bool) -> Self {
+        self.char_literal_coercion = char_literal_coercion;
+        self
+    }
+
+    pub fn implicit_push(mut self, implicit_push: bool) -> Self {
+        self.implicit_push = implicit_push;
+        self
+    }
+
+    pub fn on_custom_stmt(mut self, on_custom_stmt: CustomStmtParser) -> Self {
+        self.on_custom_stmt = Some(on_custom_stmt);
+        self
+    }
+}
+
+/// Which way a chain of equal-precedence operators groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+/// Parsing rule for one binary operator.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryOperator {
+    pub token: TokenKind,
+    /// Higher binds tighter.
+    pub precedence: u8,
+    pub assoc: Assoc,
+}
+
+/// Every binary operator, loosest first. Prefix `-` and `+` bind tighter than
+/// all of them, and calls tighter still.
+pub const BINARY_OPERATORS: &[BinaryOperator] = &[
+    BinaryOperator { token: TokenKind::Lt, precedence: 1, assoc: Assoc::Left },
+    BinaryOperator { token: TokenKind::Gt, precedence: 1, assoc: Assoc::Left },
+    BinaryOperator { token: TokenKind::Plus, precedence: 2, assoc: Assoc::Left },
+    BinaryOperator { token: TokenKind::Sub, precedence: 2, assoc: Assoc::Left },
+    BinaryOperator { token: TokenKind::Mul, precedence: 3, assoc: Assoc::Left },
+    BinaryOperator { token: TokenKind::Div, precedence: 3, assoc: Assoc::Left },
+];
+
+/// Every prefix operator. They bind tighter than all binary operators.
+pub const PREFIX_OPERATORS: &[TokenKind] = &[TokenKind::Sub, TokenKind::Plus];
+
+fn binary_operator(kind: TokenKind) -> Option<BinaryOperator> {
+    BINARY_OPERATORS.iter().find(|op| op.token == kind).copied()
+}
+
+/// Renders the operator precedence and associativity table as Markdown.
+pub fn operators_markdown() -> String {
+    let mut out = String::from("|Operator|Precedence|Associativity|\n|---|---|---|\n");
+    for op in BINARY_OPERATORS {
+        out += &format!("|{}|{}|{:?}|\n", op.token, op.precedence, op.assoc);
+    }
+    out += "\nPrefix '-' and '+' bind tighter than every binary operator, and calls tighter still.\n";
+    out
 }
 
 pub struct Parser<'a> {
-    lexer: &'a mut Lexer<'a>,
+    lexer: Lexer<'a>,
+    options: ParserOptions,
+    depth: usize,
+    block_depth: usize,
+    /// Parentheses consumed but not yet closed, so argument recovery knows
+    /// how many `)` belong to the broken argument.
+    open_parens: usize,
+    /// Informational messages for the user, such as the first
+    /// character-literal coercion.
+    notes: Vec<String>,
+    todos: Vec<TodoSite>,
+    /// Errors in call arguments that parsing recovered from, still to be
+    /// returned.
+    recovered: Vec<ParseError>,
+    /// Set once an error is returned, ending iteration.
+    failed: bool,
+}
+
+/// Where a `todo` statement was parsed, for listing unfinished code.
+#[derive(Debug, Clone)]
+pub struct TodoSite {
+    pub line: usize,
+    pub col: usize,
+    pub message: Option<String>,
+}
+
+/// Parses a whole program with the default options.
+pub fn parse_source(src: &str) -> Result<Stmt, ParseError> {
+    Parser::new(Lexer::new(src)).parse()
+}
+
+/// Yields top-level statements one at a time, consuming each terminating `;`.
+/// Iteration ends after the first statement with an error. Parsing recovers
+/// from errors inside call arguments, so such a statement can yield several
+/// errors, in source order.
+impl Iterator for Parser<'_> {
+    type Item = Result<Stmt, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.recovered.is_empty() {
+            return Some(Err(self.recovered.remove(0)));
+        }
+        if self.failed {
+            return None;
+        }
+        let result = self.parse_terminated_stmt();
+        self.failed = result.is_err() || !self.recovered.is_empty();
+        if !self.recovered.is_empty() {
+            self.recovered.extend(result.err());
+            return Some(Err(self.recovered.remove(0)));
+        }
+        result.transpose()
+    }
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(lexer: &'a mut Lexer<'a>) -> Parser {
+    pub fn new(lexer: Lexer<'a>) -> Parser<'a> {
+        Self::with_options(lexer, ParserOptions::default())
+    }
+
+    pub fn with_options(lexer: Lexer<'a>, options: ParserOptions) -> Parser<'a> {
         Parser {
             lexer,
+            options,
+            depth: 0,
+            block_depth: 0,
+            open_parens: 0,
+            notes: Vec::new(),
+            todos: Vec::new(),
+            recovered: Vec::new(),
+            failed: false,
         }
     }
 
-    pub fn parse(mut self) -> Stmt {
-        self.parse_stmt_seq()
+    pub fn lexer(&self) -> &Lexer<'a> {
+        &self.lexer
     }
 
-    fn parse_stmt_seq(&mut self) -> Stmt {
-        let mut stmts = vec![];
-        loop {
-            match self.parse_stmt() {
-                Some(stmt) => stmts.push(stmt),
-                None => break,
+    /// Informational messages produced so far, in source order.
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    /// Every `todo` statement parsed so far, in source order.
+    pub fn todos(&self) -> &[TodoSite] {
+        &self.todos
+    }
+
+    /// The underlying lexer, for custom statement parsers to consume tokens
+    /// with `expect_exact` and `expect_kind`.
+    pub fn lexer_mut(&mut self) -> &mut Lexer<'a> {
+        &mut self.lexer
+    }
+
+    pub fn parse(self) -> Result<Stmt, ParseError> {
+        self.collect::<Result<_, _>>().map(Stmt::StmtSeq)
+    }
+
+    /// Parses a statement and its `;`, which directive blocks and statements
+    /// ending in a `}` don't take.
+    fn parse_terminated_stmt(&mut self) -> Result<Option<Stmt>, ParseError> {
+        if self.lexer.lookahead == Some(Token::Push) {
+            return self.parse_push().map(Some);
+        }
+        let Some(stmt) = self.parse_stmt()? else { return Ok(None) };
+        if !matches!(stmt, Stmt::CfgBlock { .. } | Stmt::FuncDef { .. } | Stmt::CompareIf { .. }) {
+            self.lexer.expect_exact(&Token::Semi)?;
+        }
+        Ok(Some(stmt))
+    }
+
+    /// Parses `push expr;`. Under `char_literal_coercion`, a one-character
+    /// string pushed right before a `bbq` becomes the code point `bbq`
+    /// expects.
+    fn parse_push(&mut self) -> Result<Stmt, ParseError> {
+        self.lexer.step_token();
+        let start = self.lexer.token_start();
+        let operand = self.parse_expr()?;
+        let span = Span { start, end: self.lexer.prev_span().end };
+        self.lexer.expect_exact(&Token::Semi)?;
+        if self.lexer.lookahead == Some(Token::Bbq) {
+            return Ok(Stmt::Push(self.coerce_char(operand, span)?));
+        }
+        Ok(Stmt::Push(operand))
+    }
+
+    fn parse_cfg_block(&mut self) -> Result<Stmt, ParseError> {
+        let span = self.lexer.token_span();
+        self.enter_block()?;
+        self.lexer.step_token();
+
+        let flag = match self.lexer.expect_kind(TokenKind::Identifier)? {
+            Token::Identifier(flag) => flag,
+            _ => unreachable!(),
+        };
+
+        let mut body = Vec::new();
+        while self.lexer.lookahead != Some(Token::CfgEnd) {
+            match self.parse_terminated_stmt()? {
+                Some(stmt) => body.push(stmt),
+                None => return Err(self.lexer.error_at(span, "Missing @end for the @if".to_owned())),
             }
-            self.lexer.match_token(Token::Semi);
         }
-        Stmt::StmtSeq(stmts)
+        self.lexer.step_token();
+        self.block_depth -= 1;
+
+        Ok(Stmt::CfgBlock { flag, body })
+    }
+
+    fn parse_func_def(&mut self) -> Result<Stmt, ParseError> {
+        let span = self.lexer.token_span();
+        let (line, col) = self.lexer.token_pos();
+        let overrides = self.lexer.lookahead == Some(Token::Override);
+        if overrides {
+            self.lexer.step_token();
+        }
+        self.lexer.expect_exact(&Token::Build)?;
+
+        let name = self.parse_identifier()?;
+        self.lexer.expect_exact(&Token::LParen)?;
+        let mut params = Vec::new();
+        while self.lexer.lookahead != Some(Token::RParen) {
+            if !params.is_empty() {
+                self.lexer.expect_exact(&Token::Comma)?;
+            }
+            let span = self.lexer.token_span();
+            let param = self.parse_identifier()?;
+            if params.contains(&param) {
+                return Err(self.lexer.error_at(span, format!("Parameter '{}' is declared twice", param)));
+            }
+            params.push(param);
+        }
+        self.lexer.step_token();
+        let body = self.parse_block(span, &format!("the body of '{}'", name))?;
+
+        Ok(Stmt::FuncDef { name, params, body, overrides, line, col })
+    }
+
+    /// Parses `compare` after its keyword: a bare `compare;`, or
+    /// `compare { ... }` with an optional `fr { ... }` for when the top two
+    /// values differ.
+    fn parse_compare(&mut self, span: Span) -> Result<Stmt, ParseError> {
+        if self.lexer.lookahead != Some(Token::LBrace) {
+            return Ok(Stmt::Cmp);
+        }
+        let then = self.parse_block(span, "the compare block")?;
+        let otherwise = if self.lexer.lookahead == Some(Token::Fr) {
+            let span = self.lexer.token_span();
+            self.lexer.step_token();
+            self.parse_block(span, "the fr block")?
+        } else {
+            Vec::new()
+        };
+        Ok(Stmt::CompareIf { then, otherwise })
+    }
+
+    /// Enters a block, failing once that would pass `max_block_nesting`.
+    /// The caller leaves it again.
+    fn enter_block(&mut self) -> Result<(), ParseError> {
+        if self.block_depth == self.options.max_block_nesting {
+            return Err(self.lexer.error(format!(
+                "Block nesting exceeds the limit of {}",
+                self.options.max_block_nesting
+            )));
+        }
+        self.block_depth += 1;
+        Ok(())
+    }
+
+    /// Parses `{ statements }`; `span` is the construct the block belongs to,
+    /// reported if the block is never closed.
+    fn parse_block(&mut self, span: Span, what: &str) -> Result<Vec<Stmt>, ParseError> {
+        self.lexer.expect_exact(&Token::LBrace)?;
+        self.enter_block()?;
+        let mut body = Vec::new();
+        while self.lexer.lookahead != Some(Token::RBrace) {
+            match self.parse_terminated_stmt()? {
+                Some(stmt) => body.push(stmt),
+                None => return Err(self.lexer.error_at(span, format!("Missing '}}' for {}", what))),
+            }
+        }
+        self.lexer.step_token();
+        self.block_depth -= 1;
+        Ok(body)
+    }
+
+    fn parse_identifier(&mut self) -> Result<String, ParseError> {
+        match self.lexer.expect_kind(TokenKind::Identifier)? {
+            Token::Identifier(name) => Ok(name),
+            _ => unreachable!(),
+        }
     }
 
-    fn parse_stmt(&mut self) -> Option<Stmt> {
-        match self.lexer.lookahead {
+    fn parse_stmt(&mut self) -> Result<Option<Stmt>, ParseError> {
+        let stmt = match self.lexer.lookahead {
             Some(Token::Axe) => {
                 self.lexer.step_token();
-                Some(Stmt::Axe)
+                Stmt::Axe
             }
             Some(Token::Chicken) => {
                 self.lexer.step_token();
-                Some(Stmt::Chicken)
+                Stmt::Chicken
             }
             Some(Token::Add) => {
                 self.lexer.step_token();
-                Some(Stmt::Add)
+                Stmt::Add
             }
             Some(Token::Fox) => {
                 self.lexer.step_token();
-                Some(Stmt::Fox)
+                Stmt::Fox
             }
             Some(Token::Rooster) => {
                 self.lexer.step_token();
-                Some(Stmt::Rooster)
+                Stmt::Rooster
             }
             Some(Token::Cmp) => {
+                let span = self.lexer.token_span();
                 self.lexer.step_token();
-                Some(Stmt::Cmp)
+                self.parse_compare(span)?
             }
             Some(Token::Pick) => {
                 self.lexer.step_token();
-                Some(Stmt::Pick)
+                Stmt::Pick(self.parse_immediate("pick")?)
             }
             Some(Token::Peck) => {
                 self.lexer.step_token();
-                Some(Stmt::Peck)
+                Stmt::Peck(self.parse_immediate("peck")?)
             }
             Some(Token::Fr) => {
                 self.lexer.step_token();
-                Some(Stmt::Fr)
+                Stmt::Fr
             }
             Some(Token::Bbq) => {
                 self.lexer.step_token();
-                Some(Stmt::Bbq)
+                Stmt::Bbq
+            }
+            Some(Token::Todo) => {
+                let (line, col) = self.lexer.token_pos();
+                self.lexer.step_token();
+                let message = match self.lexer.lookahead {
+                    Some(Token::String(_)) => match self.lexer.expect_kind(TokenKind::String)? {
+                        Token::String(message) => Some(message),
+                        _ => unreachable!(),
+                    },
+                    _ => None,
+                };
+                self.todos.push(TodoSite { line, col, message: message.clone() });
+                Stmt::Todo(message)
+            }
+            Some(Token::Let) => {
+                self.lexer.step_token();
+                let name = self.parse_identifier()?;
+                self.lexer.expect_exact(&Token::Eq)?;
+                Stmt::Ass(name, self.parse_expr()?)
             }
-            Some(Token::Push) => {
+            Some(Token::Build | Token::Override) => self.parse_func_def()?,
+            Some(Token::Hatch) => {
                 self.lexer.step_token();
-                Some(Stmt::Push(self.parse_expr()))
+                let start = self.lexer.token_start();
+                let call = self.parse_expr()?;
+                if !matches!(call, Expr::FunctionCall { .. }) {
+                    let span = Span { start, end: self.lexer.prev_span().end };
+                    return Err(self.lexer.error_at(span, "Expected a function call after hatch".to_owned()));
+                }
+                Stmt::Push(call)
             }
-            None => None,
-            _ => {
-                println!("Unexpected token on line {} column {}", self.lexer.line, self.lexer.col);
-                println!("char: {:?}", self.lexer.cur_char);
-                self.lexer.point_error()
+            Some(Token::CfgIf) => self.parse_cfg_block()?,
+            Some(Token::Custom(id)) if self.options.on_custom_stmt.is_some() => {
+                let pos = self.lexer.token_pos();
+                self.lexer.step_token();
+                let mut stmt = (self.options.on_custom_stmt.unwrap())(id, self)?;
+                if let Stmt::Custom { line, col, .. } = &mut stmt {
+                    (*line, *col) = pos;
+                }
+                stmt
             }
+            Some(
+                Token::Int(_) | Token::Float(_) | Token::Identifier(_) | Token::String(_)
+                    | Token::LParen | Token::Sub | Token::Plus
+            ) if self.options.implicit_push => Stmt::Push(self.parse_expr()?),
+            None => return self.lexer.take_error().map_or(Ok(None), Err),
+            Some(ref tok) => return Err(self.lexer.error(format!("Unexpected {}", tok.kind()))),
+        };
+        Ok(Some(stmt))
+    }
+
+    /// Parses the optional operand of `pick N;`/`peck N;`, which must fold to a
+    /// non-negative constant.
+    fn parse_immediate(&mut self, instr: &str) -> Result<Option<u32>, ParseError> {
+        if self.lexer.lookahead == Some(Token::Semi) {
+            return Ok(None);
+        }
+
+        let start = self.lexer.token_start();
+        let operand = self.parse_expr()?;
+        let span = Span { start, end: self.lexer.prev_span().end };
+        match operand.eval_const().map(u32::try_from) {
+            Some(Ok(n)) => Ok(Some(n)),
+            Some(Err(_)) => Err(self.lexer.error_at(
+                span,
+                format!("Operand of {} must be a non-negative integer that fits in 32 bits", instr),
+            )),
+            None => Err(self.lexer.error_at(
+                span,
+                format!("Operand of {} must be a constant integer expression", instr),
+            )),
         }
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        let left = self.parse_term();
-        self.parse_expr_tail(left)
+    pub fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(0)
     }
 
-    fn parse_expr_tail(&mut self, left: Expr) -> Expr {
-        match self.lexer.lookahead {
-            Some(Token::Plus) => {
-                self.lexer.match_token(Token::Plus);
-                let right = self.parse_term();
-                self.parse_expr_tail(Expr::BinOp {
-                    op: Token::Plus,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
+    /// Precedence climbing over `BINARY_OPERATORS`: parses operands joined by
+    /// operators binding at least as tightly as `min_precedence`. Each
+    /// operator nests everything before it one level deeper, so it counts
+    /// toward `max_nesting` like a parenthesis until the chain ends.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let start = self.lexer.token_start();
+        let depth = self.depth;
+        let mut left = self.parse_factor()?;
+        while let Some(op) = self.lexer.lookahead.as_ref().and_then(|tok| binary_operator(tok.kind())) {
+            if op.precedence < min_precedence {
+                break;
+            }
+            let left_span = Span { start, end: self.lexer.prev_span().end };
+            let op_span = self.lexer.token_span();
+            let tok = self.lexer.expect_kind(op.token)?;
+            self.depth += 1;
+            let right_start = self.lexer.token_start();
+            let mut right = self.parse_binary(match op.assoc {
+                Assoc::Left => op.precedence + 1,
+                Assoc::Right => op.precedence,
+            })?;
+            if tok != Token::Plus {
+                left = self.coerce_char(left, left_span)?;
+                right = self.coerce_char(right, Span { start: right_start, end: self.lexer.prev_span().end })?;
             }
-            Some(Token::Sub) => {
-                self.lexer.match_token(Token::Sub);
-                let right = self.parse_term();
-                self.parse_expr_tail(Expr::BinOp {
-                    op: Token::Sub,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
+            if tok != Token::Plus && (left.is_string() || right.is_string()) {
+                return Err(self.lexer.error_at(op_span, format!("Strings can't be used with {}", tok.kind())));
             }
-            _ => left,
+            left = Expr::BinOp {
+                op: tok,
+                left: Box::new(left),
+                right: Box::new(right),
+                span: Span { start, end: self.lexer.prev_span().end },
+            };
         }
+        self.depth = depth;
+        Ok(left)
     }
 
-    fn parse_term(&mut self) -> Expr {
-        let left = self.parse_factor();
-        self.parse_term_tail(left)
-    }
+    /// Parses a primary expression followed by any number of call suffixes.
+    /// Only names are callable, so `(f)(1)` is a call but `(1)(2)` is not.
+    fn parse_factor(&mut self) -> Result<Expr, ParseError> {
+        let start = self.lexer.token_start();
+        let (line, col) = self.lexer.token_pos();
+        if self.depth == self.options.max_nesting {
+            return Err(self.lexer.error(format!(
+                "Expression nesting exceeds the limit of {}",
+                self.options.max_nesting
+            )));
+        }
+        self.depth += 1;
+        let mut expr = self.parse_primary()?;
 
-    fn parse_term_tail(&mut self, left: Expr) -> Expr {
-        match self.lexer.lookahead {
-            Some(Token::Mul) => {
-                self.lexer.match_token(Token::Mul);
-                let right = self.parse_factor();
-                self.parse_term_tail(Expr::BinOp {
-                    op: Token::Mul,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
-            }
-            Some(Token::Div) => {
-                self.lexer.match_token(Token::Div);
-                let right = self.parse_factor();
-                self.parse_term_tail(Expr::BinOp {
-                    op: Token::Div,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
-            }
-            _ => left,
+        while self.lexer.lookahead == Some(Token::LParen) {
+            let name = match expr {
+                Expr::Variable { name, .. } => name,
+                _ => {
+                    let span = Span { start, end: self.lexer.prev_span().end };
+                    return Err(self.lexer.error_at(span, "Expression is not callable".to_owned()));
+                }
+            };
+            self.lexer.step_token();
+            self.open_parens += 1;
+            let args = self.parse_argument_list(&name)?;
+            self.lexer.expect_exact(&Token::RParen)?;
+            self.open_parens -= 1;
+            let span = Span { start, end: self.lexer.prev_span().end };
+            expr = Expr::FunctionCall { name, args, line, col, span };
         }
+        self.depth -= 1;
+        Ok(expr)
     }
 
-    fn parse_factor(&mut self) -> Expr {
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
         match self.lexer.lookahead.clone() {
             Some(Token::Int(num)) => {
                 self.lexer.step_token();
-                Expr::Int(num)
+                Ok(Expr::Int(num))
             }
             Some(op @ Token::Sub) | Some(op @ Token::Plus) => {
+                let op_span = self.lexer.token_span();
                 self.lexer.step_token();
-                Expr::UnOp { op, operand: Box::new(self.parse_factor()) }
+                let start = self.lexer.token_start();
+                let operand = self.parse_factor()?;
+                let operand = self.coerce_char(operand, Span { start, end: self.lexer.prev_span().end })?;
+                if operand.is_string() {
+                    return Err(self.lexer.error_at(op_span, format!("Strings can't be used with unary {}", op.kind())));
+                }
+                let span = Span { start: op_span.start, end: self.lexer.prev_span().end };
+                Ok(Expr::UnOp { op, operand: Box::new(operand), span })
             }
             Some(Token::Float(num)) => {
                 self.lexer.step_token();
-                Expr::Float(num)
+                Ok(Expr::Float(num))
             }
             Some(Token::LParen) => {
                 self.lexer.step_token();
-                let expr = self.parse_expr();
-                self.lexer.match_token(Token::RParen);
-                expr
+                self.open_parens += 1;
+                let expr = self.parse_expr()?;
+                self.lexer.expect_exact(&Token::RParen)?;
+                self.open_parens -= 1;
+                Ok(expr)
             }
             Some(Token::Identifier(name)) => {
+                let (line, col) = self.lexer.token_pos();
                 self.lexer.step_token();
-                if let Some(Token::LParen) = self.lexer.lookahead {
-                    self.lexer.step_token();
-                    let args = self.parse_argument_list();
-                    self.lexer.match_token(Token::RParen);
-                    Expr::FunctionCall { name: name.clone(), args }
-                } else {
-                    Expr::Variable(name.clone())
-                }
+                Ok(Expr::Variable { name, line, col })
             }
-            _ => {
-                println!(
-                    "\n\n\nExpected an expression on line {} column {}, got {}:",
-                    self.lexer.line,
-                    self.lexer.col,
-                    match self.lexer.cur_char {
-                        Some(ch) => format!("{:?}", ch),
-                        None => "None".to_owned()
-                    }
-                );
-                self.lexer.point_error();
+            Some(Token::String(text)) => {
+                self.lexer.step_token();
+                Ok(Expr::String(text))
             }
+            None => Err(self.lexer.eof_error("an expression")),
+            Some(tok) => Err(self.lexer.error(format!("Expected an expression, got {}", tok.kind()))),
         }
     }
 
-    fn parse_argument_list(&mut self) -> Vec<Expr> {
+    /// Lowers a one-character string literal at `span` to its code point when
+    /// `char_literal_coercion` is on, for places that expect a number. Longer
+    /// strings can't be coerced and are an error; any other expression is
+    /// returned as is.
+    fn coerce_char(&mut self, expr: Expr, span: Span) -> Result<Expr, ParseError> {
+        let Expr::String(text) = &expr else { return Ok(expr) };
+        if !self.options.char_literal_coercion {
+            return Ok(expr);
+        }
+        let mut chars = text.chars();
+        let (Some(ch), None) = (chars.next(), chars.next()) else {
+            return Err(self.lexer.error_at(
+                span,
+                format!(
+                    "Only one-character strings can be used as numbers, but this one has {} characters",
+                    text.chars().count()
+                ),
+            ));
+        };
+        if self.notes.is_empty() {
+            let (line, col) = self.lexer.source().line_col(span.start);
+            self.notes.push(format!(
+                "{:?} on line {} column {} is used as its character code {}",
+                text, line, col, ch as u32
+            ));
+        }
+        Ok(Expr::Int(i64::from(u32::from(ch))))
+    }
+
+    fn parse_argument_list(&mut self, name: &str) -> Result<Vec<Expr>, ParseError> {
         let mut args = Vec::new();
         if self.lexer.lookahead != Some(Token::RParen) {
-            args.push(self.parse_expr());
+            args.push(self.parse_argument(name, 1)?);
             while let Some(Token::Comma) = self.lexer.lookahead {
                 self.lexer.step_token();
-                args.push(self.parse_expr());
+                args.push(self.parse_argument(name, args.len() + 1)?);
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_argument(&mut self, name: &str, index: usize) -> Result<Expr, ParseError> {
+        let (depth, open_parens) = (self.depth, self.open_parens);
+        self.lexer.push_context(format!("argument {} of call to '{}'", index, name));
+        let arg = self.parse_expr();
+        self.lexer.pop_context();
+        // An error leaves both counters where the argument broke off.
+        let unclosed = self.open_parens - open_parens;
+        (self.depth, self.open_parens) = (depth, open_parens);
+        match arg {
+            Err(err) if self.skip_to_closing_paren(unclosed) => {
+                self.recovered.push(err);
+                Ok(Expr::Int(0))
+            }
+            arg => arg,
+        }
+    }
+
+    /// Skips the rest of a broken argument up to the `)` closing the call,
+    /// stepping over nested parentheses, including the `unclosed` ones the
+    /// argument opened before it broke, so their `)` isn't taken for the
+    /// call's. Returns false if the statement ends first.
+    fn skip_to_closing_paren(&mut self, unclosed: usize) -> bool {
+        let mut depth = unclosed;
+        loop {
+            match self.lexer.lookahead {
+                Some(Token::RParen) if depth == 0 => return true,
+                Some(Token::RParen) => depth -= 1,
+                Some(Token::LParen) => depth += 1,
+                Some(Token::Semi | Token::LBrace | Token::RBrace) | None => return false,
+                _ => {}
             }
+            self.lexer.step_token();
         }
-        args
     }
 }
@@ -0,0 +1,153 @@
+/// A byte range `start..end` into a source file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Source text with a precomputed line-starts table for position math. Lines
+/// and columns are 1-based and columns count chars, not bytes. `\n`, `\r\n`,
+/// and a lone `\r` all end a line. A leading byte order mark belongs to no
+/// line, so it never shifts columns or shows up in rendered lines.
+pub struct SourceFile<'a> {
+    name: Option<&'a str>,
+    text: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceFile<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self::build(None, text)
+    }
+
+    pub fn named(name: &'a str, text: &'a str) -> Self {
+        Self::build(Some(name), text)
+    }
+
+    fn build(name: Option<&'a str>, text: &'a str) -> Self {
+        let bytes = text.as_bytes();
+        let first = if text.starts_with(BOM) { BOM.len_utf8() } else { 0 };
+        let mut line_starts = vec![first];
+        for (i, &byte) in bytes.iter().enumerate().skip(first) {
+            match byte {
+                b'\n' => line_starts.push(i + 1),
+                b'\r' if bytes.get(i + 1) != Some(&b'\n') => line_starts.push(i + 1),
+                _ => {}
+            }
+        }
+        SourceFile { name, text, line_starts }
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Returns the line and column of a byte offset, clamped to the end of the
+    /// file.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.clamp(self.line_starts[0], self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let start = self.line_starts[line - 1];
+        let col = self.text[start..floor_char_boundary(self.text, offset)].chars().count() + 1;
+        (line, col)
+    }
+
+    /// Returns the byte offset of a line and column. The column just past the
+    /// last char of a line is valid and refers to the line terminator.
+    pub fn offset(&self, line: usize, col: usize) -> Option<usize> {
+        if line == 0 || line > self.line_count() {
+            return None;
+        }
+        let span = self.line_span(line);
+        let text = &self.text[span.start..span.end];
+        let mut chars = text.char_indices().map(|(i, _)| i).chain([text.len()]);
+        chars.nth(col.checked_sub(1)?).map(|i| span.start + i)
+    }
+
+    /// Returns a line's text without its terminator.
+    ///
+    /// Panics if `line` is 0 or past the last line.
+    pub fn line_text(&self, line: usize) -> &'a str {
+        let span = self.line_span(line);
+        &self.text[span.start..span.end]
+    }
+
+    /// Returns the span of a line, excluding its terminator.
+    ///
+    /// Panics if `line` is 0 or past the last line.
+    pub fn line_span(&self, line: usize) -> Span {
+        let start = self.line_starts[line - 1];
+        let end = match self.line_starts.get(line) {
+            Some(&next) if self.text[..next].ends_with("\r\n") => next - 2,
+            Some(&next) => next - 1,
+            None => self.text.len(),
+        };
+        Span { start, end }
+    }
+}
+
+/// The byte order mark some editors put at the start of UTF-8 files.
+pub const BOM: char = '\u{FEFF}';
+
+/// Number of chars of a long line echoed around a diagnostic's position.
+pub const SNIPPET_WIDTH: usize = 120;
+
+impl SourceFile<'_> {
+    /// Renders the line containing `offset` with a caret under it. Unless
+    /// `full` is set, lines longer than `SNIPPET_WIDTH` chars are cut to a
+    /// window around the caret, marked with `…` where text was elided. Tabs
+    /// are expanded to stops every `tab_width` columns so the caret lines up
+    /// however the terminal would have displayed them.
+    pub fn render_caret(&self, offset: usize, full: bool, tab_width: usize) -> String {
+        let (line, col) = self.line_col(offset);
+        let chars: Vec<char> = self.line_text(line).chars().collect();
+        let caret = col - 1;
+
+        let (start, end) = if full || chars.len() <= SNIPPET_WIDTH {
+            (0, chars.len())
+        } else {
+            let start = caret.saturating_sub(SNIPPET_WIDTH / 2).min(chars.len() - SNIPPET_WIDTH);
+            (start, start + SNIPPET_WIDTH)
+        };
+
+        let mut text = String::new();
+        let mut width = 0;
+        let mut indent = None;
+        if start > 0 {
+            text.push('…');
+            width += 1;
+        }
+        for (i, &ch) in chars[start..end].iter().enumerate() {
+            if start + i == caret {
+                indent = Some(width);
+            }
+            if ch == '\t' {
+                let stop = tab_width.max(1) - width % tab_width.max(1);
+                text += &" ".repeat(stop);
+                width += stop;
+            } else {
+                text.push(ch);
+                width += 1;
+            }
+        }
+        if end < chars.len() {
+            text.push('…');
+        }
+        format!("{}\n{}^", text, " ".repeat(indent.unwrap_or(width)))
+    }
+}
+
+fn floor_char_boundary(text: &str, mut offset: usize) -> usize {
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
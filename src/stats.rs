@@ -0,0 +1,214 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    compiler::Compiler,
+    error::ParseError,
+    lexer::Lexer,
+    parser::{Expr, Parser, Stmt},
+    source::SourceFile,
+};
+
+/// Size and shape statistics for one program, or for the part of it before
+/// its first parse error.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub tokens: usize,
+    /// Source lines containing anything other than whitespace.
+    pub lines: usize,
+    /// Statement counts per kind, including statements inside `@if` blocks.
+    pub statements: BTreeMap<&'static str, usize>,
+    pub expr_nodes: usize,
+    pub max_expr_depth: usize,
+    /// How often each literal appears, keyed by its value.
+    pub literals: BTreeMap<String, usize>,
+    /// Every build, in source order.
+    pub builds: Vec<BuildStats>,
+    /// Zero when the program doesn't compile.
+    pub instructions: usize,
+    /// The errors that stopped parsing, in source order. The other counts
+    /// cover the statements parsed before them.
+    pub errors: Vec<ParseError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildStats {
+    pub name: String,
+    /// AST nodes in the body.
+    pub size: usize,
+    /// Cyclomatic complexity: one more than the number of two-way branches,
+    /// which are the `compare` conditionals and `fr` jumps in the body.
+    pub complexity: usize,
+}
+
+impl Stats {
+    pub fn collect(src: &str) -> Self {
+        let mut stats = Stats::default();
+
+        // A lexing error stops the buffering, and the parser reports it where
+        // it occurs.
+        let mut lexer = Lexer::new(src);
+        lexer.buffer(|_, _| stats.tokens += 1);
+
+        let file = SourceFile::new(src);
+        stats.lines = (1..=file.line_count()).filter(|&line| !file.line_text(line).trim().is_empty()).count();
+
+        let mut stmts = Vec::new();
+        for result in Parser::new(lexer) {
+            match result {
+                Ok(stmt) => stmts.push(stmt),
+                Err(err) => stats.errors.push(err),
+            }
+        }
+        let ast = Stmt::StmtSeq(stmts);
+        stats.visit_stmt(&ast);
+        stats.instructions = Compiler::new().compile(&ast).map_or(0, |code| code.len());
+
+        stats
+    }
+
+    pub fn instructions_per_line(&self) -> f64 {
+        if self.lines == 0 {
+            0.0
+        } else {
+            self.instructions as f64 / self.lines as f64
+        }
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        let kind = match stmt {
+            Stmt::StmtSeq(seq) => {
+                seq.iter().for_each(|stmt| self.visit_stmt(stmt));
+                return;
+            }
+            Stmt::Axe => "axe",
+            Stmt::Chicken => "chicken",
+            Stmt::Add => "add",
+            Stmt::Fox => "fox",
+            Stmt::Rooster => "rooster",
+            Stmt::Cmp => "compare",
+            Stmt::Pick(_) => "pick",
+            Stmt::Peck(_) => "peck",
+            Stmt::Fr => "fr",
+            Stmt::Bbq => "bbq",
+            Stmt::Todo(_) => "todo",
+            Stmt::Push(expr) => {
+                self.visit_expr(expr, 1);
+                "push"
+            }
+            Stmt::CfgBlock { body, .. } => {
+                body.iter().for_each(|stmt| self.visit_stmt(stmt));
+                "@if"
+            }
+            Stmt::Custom { args, .. } => {
+                args.iter().for_each(|arg| self.visit_expr(arg, 1));
+                "custom"
+            }
+            Stmt::Ass(_, expr) => {
+                self.visit_expr(expr, 1);
+                "let"
+            }
+            Stmt::CompareIf { then, otherwise } => {
+                then.iter().chain(otherwise).for_each(|stmt| self.visit_stmt(stmt));
+                "compare"
+            }
+            Stmt::FuncDef { name, body, .. } => {
+                self.builds.push(BuildStats {
+                    name: name.clone(),
+                    size: body.iter().map(Stmt::node_count).sum(),
+                    complexity: 1 + body.iter().map(branches).sum::<usize>(),
+                });
+                body.iter().for_each(|stmt| self.visit_stmt(stmt));
+                "build"
+            }
+        };
+        *self.statements.entry(kind).or_default() += 1;
+    }
+
+    fn visit_expr(&mut self, expr: &Expr, depth: usize) {
+        self.expr_nodes += 1;
+        self.max_expr_depth = self.max_expr_depth.max(depth);
+        match expr {
+            Expr::Int(num) => *self.literals.entry(num.to_string()).or_default() += 1,
+            Expr::Float(num) => *self.literals.entry(format!("{:?}", num)).or_default() += 1,
+            Expr::String(text) => *self.literals.entry(format!("{:?}", text)).or_default() += 1,
+            Expr::BinOp { left, right, .. } => {
+                self.visit_expr(left, depth + 1);
+                self.visit_expr(right, depth + 1);
+            }
+            Expr::UnOp { operand, .. } => self.visit_expr(operand, depth + 1),
+            Expr::FunctionCall { args, .. } => {
+                args.iter().for_each(|arg| self.visit_expr(arg, depth + 1));
+            }
+            Expr::Variable { .. } => {}
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        fn counts<K: fmt::Debug>(map: &BTreeMap<K, usize>) -> String {
+            map.iter().map(|(k, v)| format!("{:?}: {}", k, v)).collect::<Vec<_>>().join(", ")
+        }
+        let builds: Vec<_> = self
+            .builds
+            .iter()
+            .map(|build| {
+                let BuildStats { name, size, complexity } = build;
+                format!("{{\"name\": {:?}, \"size\": {}, \"complexity\": {}}}", name, size, complexity)
+            })
+            .collect();
+        let errors: Vec<_> = self.errors.iter().map(|err| format!("{:?}", err.to_string())).collect();
+        format!(
+            "{{\n  \"tokens\": {},\n  \"lines\": {},\n  \"statements\": {{{}}},\n  \"expr_nodes\": {},\n  \
+             \"max_expr_depth\": {},\n  \"literals\": {{{}}},\n  \"builds\": [{}],\n  \"instructions\": {},\n  \
+             \"instructions_per_line\": {:.2},\n  \"errors\": [{}]\n}}\n",
+            self.tokens,
+            self.lines,
+            counts(&self.statements),
+            self.expr_nodes,
+            self.max_expr_depth,
+            counts(&self.literals),
+            builds.join(", "),
+            self.instructions,
+            self.instructions_per_line(),
+            errors.join(", "),
+        )
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Tokens:               {}", self.tokens)?;
+        writeln!(f, "Source lines:         {}", self.lines)?;
+        writeln!(f, "Statements:           {}", self.statements.values().sum::<usize>())?;
+        for (kind, count) in &self.statements {
+            writeln!(f, "    {:<17} {}", kind, count)?;
+        }
+        writeln!(f, "Expression nodes:     {}", self.expr_nodes)?;
+        writeln!(f, "Max expression depth: {}", self.max_expr_depth)?;
+        writeln!(f, "Literals:")?;
+        for (literal, count) in &self.literals {
+            writeln!(f, "    {:<17} {}", literal, count)?;
+        }
+        writeln!(f, "Builds:               {}", self.builds.len())?;
+        for build in &self.builds {
+            writeln!(f, "    {:<17} size {}, complexity {}", build.name, build.size, build.complexity)?;
+        }
+        writeln!(f, "Instructions:         {}", self.instructions)?;
+        writeln!(f, "Instructions/line:    {:.2}", self.instructions_per_line())?;
+        if !self.errors.is_empty() {
+            writeln!(f, "Parse errors:         {} (counts cover the statements before them)", self.errors.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// The two-way branches in a statement and the statements inside it.
+fn branches(stmt: &Stmt) -> usize {
+    match stmt {
+        Stmt::Fr => 1,
+        Stmt::CompareIf { then, otherwise } => 1 + then.iter().chain(otherwise).map(branches).sum::<usize>(),
+        Stmt::StmtSeq(body) | Stmt::CfgBlock { body, .. } | Stmt::FuncDef { body, .. } => {
+            body.iter().map(branches).sum()
+        }
+        _ => 0,
+    }
+}
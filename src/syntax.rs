@@ -0,0 +1,157 @@
+//! A description of the surface syntax built from the lexer's and parser's own
+//! tables, for documentation and editor syntax highlighting.
+
+use crate::{
+    compiler::{self, isa},
+    lexer::{TokenKind, COMMENTS, DIRECTIVES, KEYWORD_NAMES},
+    parser::{Assoc, BINARY_OPERATORS, PREFIX_OPERATORS},
+};
+
+pub const INT_PATTERN: &str = r"[0-9]+";
+pub const FLOAT_PATTERN: &str = r"[0-9]+\.[0-9]*";
+pub const STRING_PATTERN: &str = r#""(?:[^"\\]|\\[nt"\\])*""#;
+pub const IDENTIFIER_PATTERN: &str = r"[A-Za-z_][A-Za-z0-9_]*";
+
+/// Keywords that are also instruction mnemonics are instructions; the rest
+/// are plain keywords.
+fn keyword_category(word: &str) -> &'static str {
+    if isa().iter().any(|info| info.mnemonic == word) {
+        "instruction"
+    } else {
+        "keyword"
+    }
+}
+
+fn symbol(kind: TokenKind) -> String {
+    kind.to_string().trim_matches('\'').to_owned()
+}
+
+/// Renders the syntax description as a JSON object.
+pub fn syntax_json() -> String {
+    let keywords: Vec<_> = KEYWORD_NAMES
+        .iter()
+        .map(|word| format!("    {{\"word\": {:?}, \"category\": {:?}}}", word, keyword_category(word)))
+        .collect();
+    let directives: Vec<_> = DIRECTIVES.iter().map(|(name, _)| format!("{:?}", format!("@{}", name))).collect();
+    let mut operators: Vec<_> = BINARY_OPERATORS
+        .iter()
+        .map(|op| {
+            format!(
+                "    {{\"symbol\": {:?}, \"fixity\": \"infix\", \"precedence\": {}, \"associativity\": {:?}}}",
+                symbol(op.token),
+                op.precedence,
+                match op.assoc {
+                    Assoc::Left => "left",
+                    Assoc::Right => "right",
+                }
+            )
+        })
+        .collect();
+    operators.extend(
+        PREFIX_OPERATORS.iter().map(|&kind| format!("    {{\"symbol\": {:?}, \"fixity\": \"prefix\"}}", symbol(kind))),
+    );
+    let comments: Vec<_> = COMMENTS
+        .iter()
+        .map(|(open, close)| match close {
+            Some(close) => format!("    {{\"kind\": \"block\", \"open\": {:?}, \"close\": {:?}}}", open, close),
+            None => format!("    {{\"kind\": \"line\", \"open\": {:?}}}", open),
+        })
+        .collect();
+
+    format!(
+        "{{\n  \"keywords\": [\n{}\n  ],\n  \"directives\": [{}],\n  \"operators\": [\n{}\n  ],\n  \
+         \"comments\": [\n{}\n  ],\n  \"patterns\": {{\n    \"integer\": {:?},\n    \"float\": {:?},\n    \
+         \"string\": {:?},\n    \"identifier\": {:?}\n  }},\n  \"instructions\": {}}}\n",
+        keywords.join(",\n"),
+        directives.join(", "),
+        operators.join(",\n"),
+        comments.join(",\n"),
+        INT_PATTERN,
+        FLOAT_PATTERN,
+        STRING_PATTERN,
+        IDENTIFIER_PATTERN,
+        compiler::isa_json().trim_end(),
+    )
+}
+
+fn words(category: &str) -> Vec<&'static str> {
+    KEYWORD_NAMES.iter().copied().filter(|word| keyword_category(word) == category).collect()
+}
+
+/// Renders a TextMate grammar (`.tmLanguage.json`).
+pub fn textmate_grammar() -> String {
+    let rule = |scope: &str, pattern: String| format!("    {{\"name\": \"{}.eggsembly\", \"match\": {:?}}}", scope, pattern);
+    let mut rules: Vec<_> = COMMENTS
+        .iter()
+        .map(|(open, close)| match close {
+            Some(close) => format!(
+                "    {{\"name\": \"comment.block.eggsembly\", \"begin\": {:?}, \"end\": {:?}}}",
+                regex_escape(open),
+                regex_escape(close)
+            ),
+            None => rule("comment.line", format!("{}.*$", regex_escape(open))),
+        })
+        .collect();
+    let directives: Vec<_> = DIRECTIVES.iter().map(|(name, _)| *name).collect();
+    let mut operators = String::new();
+    for kind in BINARY_OPERATORS.iter().map(|op| op.token).chain(PREFIX_OPERATORS.iter().copied()) {
+        let symbol = regex_escape(&symbol(kind));
+        if !operators.contains(&symbol) {
+            operators += &symbol;
+        }
+    }
+    rules.extend([
+        rule("string.quoted.double", STRING_PATTERN.to_owned()),
+        rule("keyword.control", format!(r"\b(?:{})\b", words("keyword").join("|"))),
+        rule("support.function.instruction", format!(r"\b(?:{})\b", words("instruction").join("|"))),
+        rule("keyword.other.directive", format!(r"@(?:{})\b", directives.join("|"))),
+        rule("constant.numeric.float", format!(r"\b{}", FLOAT_PATTERN)),
+        rule("constant.numeric.integer", format!(r"\b{}\b", INT_PATTERN)),
+        rule("keyword.operator", format!("[{}]", operators)),
+    ]);
+
+    format!(
+        "{{\n  \"name\": \"Eggsembly\",\n  \"scopeName\": \"source.eggsembly\",\n  \"fileTypes\": [\"egg\"],\n  \
+         \"patterns\": [\n{}\n  ]\n}}\n",
+        rules.join(",\n")
+    )
+}
+
+/// Renders a Vim syntax file.
+pub fn vim_syntax() -> String {
+    let mut out = String::from(
+        "\" Vim syntax file\n\" Language: Eggsembly\n\nif exists(\"b:current_syntax\")\n  finish\nendif\n\n",
+    );
+    out += &format!("syn keyword eggsemblyKeyword {}\n", words("keyword").join(" "));
+    out += &format!("syn keyword eggsemblyInstruction {}\n", words("instruction").join(" "));
+    let directives: Vec<_> = DIRECTIVES.iter().map(|(name, _)| *name).collect();
+    out += &format!("syn match eggsemblyDirective \"\\v\\@({})>\"\n", directives.join("|"));
+    out += "syn match eggsemblyNumber \"\\v<[0-9]+(\\.[0-9]*)?\"\n";
+    out += "syn region eggsemblyString start=+\"+ skip=+\\\\[nt\"\\\\]+ end=+\"+\n";
+    for (open, close) in COMMENTS {
+        out += &match close {
+            Some(close) => format!(
+                "syn region eggsemblyComment start=\"\\V{}\" end=\"\\V{}\"\n",
+                open.replace('\\', "\\\\"),
+                close.replace('\\', "\\\\")
+            ),
+            None => format!("syn match eggsemblyComment \"\\V{}\\.\\*$\"\n", open.replace('\\', "\\\\")),
+        };
+    }
+    out += "\nhi def link eggsemblyKeyword Keyword\nhi def link eggsemblyInstruction Statement\n\
+            hi def link eggsemblyDirective PreProc\nhi def link eggsemblyNumber Number\n\
+            hi def link eggsemblyString String\nhi def link eggsemblyComment Comment\n\n\
+            let b:current_syntax = \"eggsembly\"\n";
+    out
+}
+
+fn regex_escape(text: &str) -> String {
+    let mut out = String::new();
+    for ch in text.chars() {
+        if r"\^$.|?*+()[]{}-/".contains(ch) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
@@ -0,0 +1,482 @@
+use std::{cmp::Ordering, collections::HashMap, fmt, io, mem, rc::Rc};
+
+use crate::compiler::Code;
+
+/// A value on the stack. Chicken itself only has integers and the text that
+/// `chicken` and `bbq` produce; floats and other strings come from literals.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+}
+
+impl Value {
+    /// Zero, `0.0` and the empty string are false; everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(n) => *n != 0,
+            Value::Float(x) => *x != 0.0,
+            Value::Str(text) => !text.is_empty(),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Str(_) => "a string",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// A failure while running a program, such as dividing by zero or popping an
+/// empty stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Index of the failing instruction.
+    pub index: usize,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at instruction {}", self.message, self.index)
+    }
+}
+
+/// How `bbq` turns a number into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BbqMode {
+    /// The number is a Unicode scalar value, and anything else is an error.
+    #[default]
+    Unicode,
+    /// The number is a byte from 0 to 255, written to the output as is.
+    Byte,
+    /// As in Chicken's own interpreter: ASCII codes give their character, and
+    /// anything higher an HTML character reference such as `&#233;`.
+    ChickenCompat,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmOptions {
+    pub bbq_mode: BbqMode,
+}
+
+impl VmOptions {
+    pub fn bbq_mode(mut self, bbq_mode: BbqMode) -> Self {
+        self.bbq_mode = bbq_mode;
+        self
+    }
+}
+
+/// A function the host provides to `call`. It pops its arguments from the
+/// stack and pushes its results.
+pub type HostFunction = fn(&mut Vec<Value>) -> Result<(), String>;
+
+/// An instruction with its operand resolved by `ExecCode::link`: text is an
+/// index into the string pool and host calls an index into the host table, so
+/// dispatch neither hashes nor copies operands. `chicken` becomes a push of
+/// the pooled string "chicken".
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Axe,
+    Add,
+    Fox,
+    Rooster,
+    Compare,
+    Pick,
+    Peck,
+    Fr,
+    Bbq,
+    Push(i64),
+    PushFloat(f64),
+    PushString(usize),
+    Load(usize),
+    Store(usize),
+    Hatch(usize),
+    Return,
+    Jump(usize),
+    JumpUnless(usize),
+    CallHost(usize),
+    Div,
+    Lt,
+    Gt,
+    Trap(usize),
+}
+
+/// Compiled code linked for running. Linking checks every `hatch` and jump
+/// target and looks up every host function once, so a malformed program fails
+/// before its first instruction runs rather than partway through.
+pub struct ExecCode {
+    ops: Vec<Op>,
+    /// Each distinct string once; pushing one only bumps its count.
+    strings: Vec<Rc<str>>,
+    hosts: Vec<HostFunction>,
+}
+
+impl ExecCode {
+    pub fn link(code: &[Code], functions: &HashMap<String, HostFunction>) -> Result<Self, RuntimeError> {
+        let mut exec = ExecCode { ops: Vec::with_capacity(code.len()), strings: Vec::new(), hosts: Vec::new() };
+        // Where each string already went in `strings`, so a program pushing
+        // many distinct strings links in linear time.
+        let mut interned = HashMap::new();
+        for (index, instr) in code.iter().enumerate() {
+            let error = |message| RuntimeError { message, index };
+            let op = match instr {
+                Code::Axe => Op::Axe,
+                Code::Chicken => Op::PushString(exec.intern(&mut interned, "chicken")),
+                Code::Add => Op::Add,
+                Code::Fox => Op::Fox,
+                Code::Rooster => Op::Rooster,
+                Code::Compare => Op::Compare,
+                Code::Pick => Op::Pick,
+                Code::Peck => Op::Peck,
+                Code::Fr => Op::Fr,
+                Code::Bbq => Op::Bbq,
+                Code::Push(n) => Op::Push(*n),
+                Code::PushFloat(x) => Op::PushFloat(*x),
+                Code::PushString(text) => Op::PushString(exec.intern(&mut interned, text)),
+                Code::Load(slot) => Op::Load(*slot),
+                Code::Store(slot) => Op::Store(*slot),
+                Code::Hatch(target) if *target < code.len() => Op::Hatch(*target),
+                Code::Hatch(target) => return Err(error(format!("Call to {} is past the end of the program", target))),
+                Code::Return => Op::Return,
+                Code::Label(label) => return Err(error(format!("Label {} was never linked", label))),
+                Code::Jump(target) if *target <= code.len() => Op::Jump(*target),
+                Code::JumpUnless(target) if *target <= code.len() => Op::JumpUnless(*target),
+                Code::Jump(target) | Code::JumpUnless(target) => {
+                    return Err(error(format!("Jump to {} is past the end of the program", target)))
+                }
+                Code::CallFunc(name) => match functions.get(name) {
+                    Some(&function) => {
+                        exec.hosts.push(function);
+                        Op::CallHost(exec.hosts.len() - 1)
+                    }
+                    None => return Err(error(format!("Undefined function '{}'", name))),
+                },
+                Code::Div => Op::Div,
+                Code::Lt => Op::Lt,
+                Code::Gt => Op::Gt,
+                Code::Trap(message) => Op::Trap(exec.intern(&mut interned, message)),
+            };
+            exec.ops.push(op);
+        }
+        Ok(exec)
+    }
+
+    fn intern(&mut self, interned: &mut HashMap<Rc<str>, usize>, text: &str) -> usize {
+        if let Some(&index) = interned.get(text) {
+            return index;
+        }
+        let text: Rc<str> = text.into();
+        self.strings.push(text.clone());
+        interned.insert(text, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+/// Runs compiled code over an explicit stack.
+///
+/// Arithmetic on two integers stays integral and fails on overflow; an integer
+/// mixed with a float is converted to a float. `add` with a string operand
+/// concatenates the text of both operands, as in Chicken, and any other
+/// arithmetic on strings is an error. Comparisons push 1 or 0. `pick` and
+/// `peck` address the stack itself, counting from the bottom once their
+/// operands are popped, while `load` and `store` use variable slots kept apart
+/// from it; each `hatch` gets fresh slots until its `return`. `fr` jumps
+/// relative to the instruction after it, while `jump` and `jumpz` name the
+/// instruction index to continue at. Like Chicken, the program's output is
+/// whatever is on top of the stack when it stops.
+pub struct Vm {
+    code: Vec<Code>,
+    /// Linked on the first `run`, and again after `define`.
+    exec: Option<ExecCode>,
+    stack: Vec<Value>,
+    /// The running function's variables, `None` until first stored.
+    slots: Vec<Option<Value>>,
+    /// The return address and slots of every caller of the running function.
+    frames: Vec<(usize, Vec<Option<Value>>)>,
+    functions: HashMap<String, HostFunction>,
+    pc: usize,
+    options: VmOptions,
+}
+
+/// Deepest nesting of function calls before the program fails, so runaway
+/// recursion stops with an error instead of exhausting memory.
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+impl Vm {
+    pub fn new(code: Vec<Code>) -> Self {
+        Self::with_options(code, VmOptions::default())
+    }
+
+    pub fn with_options(code: Vec<Code>, options: VmOptions) -> Self {
+        Vm {
+            code,
+            exec: None,
+            stack: Vec::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            functions: HashMap::new(),
+            pc: 0,
+            options,
+        }
+    }
+
+    /// Makes `function` callable by name.
+    pub fn define(&mut self, name: &str, function: HostFunction) {
+        self.functions.insert(name.to_owned(), function);
+        self.exec = None;
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// The program's output: the top of the stack, if anything is on it.
+    pub fn output(&self) -> Option<&Value> {
+        self.stack.last()
+    }
+
+    /// Writes the output to `out`. In `BbqMode::Byte`, characters up to U+00FF
+    /// are written as the single byte they came from.
+    pub fn write_output(&self, out: &mut impl io::Write) -> io::Result<()> {
+        match self.output() {
+            Some(Value::Str(text)) if self.options.bbq_mode == BbqMode::Byte => {
+                for ch in text.chars() {
+                    match u8::try_from(ch) {
+                        Ok(byte) => out.write_all(&[byte])?,
+                        Err(_) => write!(out, "{}", ch)?,
+                    }
+                }
+                Ok(())
+            }
+            Some(value) => write!(out, "{}", value),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the code until `axe` or the end of the code, linking it first
+    /// unless an earlier run already did.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        let exec = match self.exec.take() {
+            Some(exec) => exec,
+            None => ExecCode::link(&self.code, &self.functions)?,
+        };
+        let result = self.execute(&exec);
+        self.exec = Some(exec);
+        result
+    }
+
+    fn execute(&mut self, exec: &ExecCode) -> Result<(), RuntimeError> {
+        while self.pc < exec.ops.len() {
+            let index = self.pc;
+            self.pc += 1;
+            let halt = self.step(exec, exec.ops[index]).map_err(|message| RuntimeError { message, index })?;
+            if halt {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes one instruction, returning whether the program stops.
+    fn step(&mut self, exec: &ExecCode, op: Op) -> Result<bool, String> {
+        match op {
+            Op::Axe => return Ok(true),
+            Op::Add => {
+                let (a, b) = self.pop_pair()?;
+                let sum = match (a, b) {
+                    (Value::Str(a), b) => Value::Str(format!("{}{}", a, b).into()),
+                    (a, Value::Str(b)) => Value::Str(format!("{}{}", a, b).into()),
+                    (a, b) => arithmetic("add", a, b, i64::checked_add, |a, b| a + b)?,
+                };
+                self.stack.push(sum);
+            }
+            Op::Fox => {
+                let (a, b) = self.pop_pair()?;
+                self.stack.push(arithmetic("subtract", a, b, i64::checked_sub, |a, b| a - b)?);
+            }
+            Op::Rooster => {
+                let (a, b) = self.pop_pair()?;
+                self.stack.push(arithmetic("multiply", a, b, i64::checked_mul, |a, b| a * b)?);
+            }
+            Op::Div => {
+                let (a, b) = self.pop_pair()?;
+                if matches!(b, Value::Int(0)) || b == Value::Float(0.0) {
+                    return Err("Division by zero".to_owned());
+                }
+                self.stack.push(arithmetic("divide", a, b, i64::checked_div, |a, b| a / b)?);
+            }
+            Op::Compare => {
+                let (a, b) = self.pop_pair()?;
+                // Numbers compare by value, so 1 equals 1.0. A string only
+                // equals the same string.
+                let equal = match (&a, &b) {
+                    (Value::Str(a), Value::Str(b)) => a == b,
+                    (Value::Str(_), _) | (_, Value::Str(_)) => false,
+                    (a, b) => order(a, b)? == Some(Ordering::Equal),
+                };
+                self.stack.push(Value::Int(equal.into()));
+            }
+            Op::Lt => {
+                let (a, b) = self.pop_pair()?;
+                self.stack.push(Value::Int((order(&a, &b)? == Some(Ordering::Less)).into()));
+            }
+            Op::Gt => {
+                let (a, b) = self.pop_pair()?;
+                self.stack.push(Value::Int((order(&a, &b)? == Some(Ordering::Greater)).into()));
+            }
+            Op::Pick => {
+                let address = self.pop_address()?;
+                let value = self.stack.get(address).ok_or_else(|| outside_stack(address))?;
+                self.stack.push(value.clone());
+            }
+            Op::Peck => {
+                let address = self.pop_address()?;
+                let value = self.pop()?;
+                *self.stack.get_mut(address).ok_or_else(|| outside_stack(address))? = value;
+            }
+            Op::Fr => {
+                let offset = match self.pop()? {
+                    Value::Int(offset) => offset,
+                    value => return Err(format!("Jump offset must be an integer, got {}", value.type_name())),
+                };
+                if self.pop()?.is_truthy() {
+                    self.pc = self
+                        .pc
+                        .checked_add_signed(offset as isize)
+                        .filter(|&target| target <= exec.ops.len())
+                        .ok_or_else(|| format!("Jump by {} leaves the program", offset))?;
+                }
+            }
+            Op::Bbq => {
+                let code = match self.pop()? {
+                    Value::Int(code) => code,
+                    value => return Err(format!("bbq needs an integer, got {}", value.type_name())),
+                };
+                let text = match self.options.bbq_mode {
+                    BbqMode::Unicode => u32::try_from(code)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or("bbq got a value that isn't a character code")?
+                        .to_string(),
+                    BbqMode::Byte => u8::try_from(code)
+                        .map(char::from)
+                        .map_err(|_| format!("bbq needs a byte from 0 to 255, got {}", code))?
+                        .to_string(),
+                    BbqMode::ChickenCompat => match u8::try_from(code) {
+                        Ok(byte) if byte.is_ascii() => char::from(byte).to_string(),
+                        _ => format!("&#{};", code),
+                    },
+                };
+                self.stack.push(Value::Str(text.into()));
+            }
+            Op::Push(n) => self.stack.push(Value::Int(n)),
+            Op::PushFloat(x) => self.stack.push(Value::Float(x)),
+            Op::PushString(text) => self.stack.push(Value::Str(Rc::clone(&exec.strings[text]))),
+            Op::Load(slot) => {
+                let value = self.slots.get(slot).and_then(Option::as_ref);
+                let value = value.ok_or_else(|| format!("Slot {} is empty", slot))?;
+                self.stack.push(value.clone());
+            }
+            Op::Store(slot) => {
+                let value = self.pop()?;
+                if slot >= self.slots.len() {
+                    self.slots.resize(slot + 1, None);
+                }
+                self.slots[slot] = Some(value);
+            }
+            Op::Jump(target) => self.pc = target,
+            Op::JumpUnless(target) => {
+                if !self.pop()?.is_truthy() {
+                    self.pc = target;
+                }
+            }
+            Op::Hatch(target) => {
+                if self.frames.len() == MAX_CALL_DEPTH {
+                    return Err(format!("Calls nested more than {} deep", MAX_CALL_DEPTH));
+                }
+                self.frames.push((self.pc, mem::take(&mut self.slots)));
+                self.pc = target;
+            }
+            Op::Return => {
+                let (pc, slots) = self.frames.pop().ok_or("return outside a function")?;
+                self.pc = pc;
+                self.slots = slots;
+            }
+            Op::CallHost(function) => (exec.hosts[function])(&mut self.stack)?,
+            Op::Trap(message) if exec.strings[message].is_empty() => return Err("not yet implemented".to_owned()),
+            Op::Trap(message) => return Err(format!("not yet implemented: {}", exec.strings[message])),
+        }
+        Ok(false)
+    }
+
+    fn pop(&mut self) -> Result<Value, String> {
+        self.stack.pop().ok_or_else(|| "Stack underflow".to_owned())
+    }
+
+    /// Pops b then a.
+    fn pop_pair(&mut self) -> Result<(Value, Value), String> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))
+    }
+
+    /// Pops an address, which the caller checks against the stack once it has
+    /// popped its other operands.
+    fn pop_address(&mut self) -> Result<usize, String> {
+        match self.pop()? {
+            Value::Int(address) => usize::try_from(address).map_err(|_| outside_stack(address)),
+            value => Err(format!("Address must be an integer, got {}", value.type_name())),
+        }
+    }
+}
+
+fn outside_stack(address: impl fmt::Display) -> String {
+    format!("Address {} is outside the stack", address)
+}
+
+/// Orders two numbers, comparing integers exactly and converting to float
+/// only when either side is one. `None` when a NaN is involved.
+fn order(a: &Value, b: &Value) -> Result<Option<Ordering>, String> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Ok(Some(a.cmp(b))),
+        (a, b) => Ok(number(a)?.partial_cmp(&number(b)?)),
+    }
+}
+
+fn number(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Int(n) => Ok(*n as f64),
+        Value::Float(x) => Ok(*x),
+        Value::Str(_) => Err("Can't compare a string by size".to_owned()),
+    }
+}
+
+fn arithmetic(
+    verb: &str,
+    a: Value,
+    b: Value,
+    int: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+) -> Result<Value, String> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => int(a, b).map(Value::Int).ok_or_else(|| "Integer overflow".to_owned()),
+        (a @ Value::Str(_), b) | (a, b @ Value::Str(_)) => {
+            Err(format!("Can't {} {} and {}", verb, a.type_name(), b.type_name()))
+        }
+        (a, b) => Ok(Value::Float(float(number(&a)?, number(&b)?))),
+    }
+}
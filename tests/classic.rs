@@ -0,0 +1,80 @@
+//! Original Eggsembly files under `LexerOptions::classic`.
+
+use parse::{
+    lexer::{Lexer, LexerOptions},
+    parser::{parse_source, Parser, Stmt},
+    source::SourceFile,
+    Compiler, Expr,
+};
+
+/// Classic programs with what `fmt --modernize` should turn them into, and
+/// the leniencies each one needs.
+const FIXTURES: &[(&str, &str, &[&str])] = &[
+    (
+        "push 72\nBBQ\npush 3\nPush 4\nADD\n",
+        "push 72;\nbbq;\npush 3;\npush 4;\nadd;\n",
+        &["newline statement separators", "case-insensitive keywords"],
+    ),
+    (
+        "push twice(21);\nbuild twice(n) {\n    push n *\n        2\n}\n",
+        "push twice(21);\nbuild twice(n) {\n    push n *\n        2;\n}\n",
+        &["newline statement separators"],
+    ),
+];
+
+fn parse_classic(src: &str) -> Stmt {
+    let lexer = Lexer::with_options(SourceFile::new(src), LexerOptions::default().classic(true));
+    Parser::new(lexer).parse().unwrap_or_else(|err| panic!("{}", err))
+}
+
+#[test]
+fn classic_fixtures_need_the_compat_mode() {
+    for &(classic, _, _) in FIXTURES {
+        assert!(parse_source(classic).is_err(), "{:?}", classic);
+    }
+}
+
+#[test]
+fn classic_fixtures_compile_like_their_modern_form() {
+    for &(classic, modern, _) in FIXTURES {
+        let modern = parse_source(modern).unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(parse_classic(classic), modern);
+        assert!(Compiler::new().compile(&modern).is_ok(), "{:?}", classic);
+    }
+}
+
+#[test]
+fn classic_fixtures_report_the_leniencies_they_used() {
+    for &(classic, modern, used) in FIXTURES {
+        let options = LexerOptions::default().classic(true);
+        let mut parser = Parser::new(Lexer::with_options(SourceFile::new(classic), options.clone()));
+        assert!(parser.by_ref().all(|stmt| stmt.is_ok()));
+        assert_eq!(parser.lexer().classic_behaviors_used(), used);
+
+        let mut parser = Parser::new(Lexer::with_options(SourceFile::new(modern), options));
+        assert!(parser.by_ref().all(|stmt| stmt.is_ok()));
+        assert!(parser.lexer().classic_behaviors_used().is_empty(), "{:?}", modern);
+    }
+}
+
+#[test]
+fn multi_line_cfg_block_takes_no_separator_after_its_flag() {
+    let ast = parse_classic("@if debug\npush 1\npush 2\n@end\npush 3\n");
+    assert_eq!(
+        ast,
+        Stmt::seq(vec![
+            Stmt::CfgBlock {
+                flag: "debug".to_owned(),
+                body: vec![Stmt::push(Expr::int(1)), Stmt::push(Expr::int(2))],
+            },
+            Stmt::push(Expr::int(3)),
+        ])
+    );
+}
+
+#[test]
+fn nested_cfg_blocks_span_lines() {
+    let ast = parse_classic("@if a\n@if b\nbbq\n@end\n@end\n");
+    let inner = Stmt::CfgBlock { flag: "b".to_owned(), body: vec![Stmt::Bbq] };
+    assert_eq!(ast, Stmt::seq(vec![Stmt::CfgBlock { flag: "a".to_owned(), body: vec![inner] }]));
+}
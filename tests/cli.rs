@@ -0,0 +1,485 @@
+//! Runs the command line on programs written to a scratch directory.
+
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// Creates an empty directory for one test, named after it.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the command line, returning whether it succeeded and what it printed.
+fn cli(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_parse")).args(args).output().unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn directory_project_compiles_its_main_file_or_all_files() {
+    let dir = scratch_dir("directory_project");
+    fs::write(dir.join("lib.egg"), "push 2;\n").unwrap();
+    fs::write(dir.join("main.egg"), "push 3;\npush 4;\nadd;\n").unwrap();
+    let dir = dir.to_str().unwrap();
+
+    let (ok, out) = cli(&["--run", dir]);
+    assert!(ok, "{}", out);
+    assert!(out.starts_with("Tokens (main.egg): "), "{}", out);
+    assert_eq!(out.lines().last(), Some("7"));
+
+    let (ok, out) = cli(&["--all", "--run", dir]);
+    assert!(ok, "{}", out);
+    let files: Vec<_> = out.lines().filter_map(|line| line.strip_prefix("Tokens (")?.split(')').next()).collect();
+    assert_eq!(files, ["lib.egg", "main.egg"]);
+    assert_eq!(out.lines().last(), Some("7"));
+}
+
+#[test]
+fn directory_without_main_lists_its_files() {
+    let dir = scratch_dir("directory_without_main");
+    fs::write(dir.join("b.egg"), "push 2;\n").unwrap();
+    fs::write(dir.join("a.egg"), "push 1;\n").unwrap();
+    fs::write(dir.join("notes.txt"), "not a program\n").unwrap();
+
+    let (ok, out) = cli(&[dir.to_str().unwrap()]);
+    assert!(!ok);
+    assert!(out.ends_with("Found these .egg files (use --all to compile them all):\n    a.egg\n    b.egg\n"), "{}", out);
+}
+
+#[test]
+fn directory_diagnostics_name_files_relative_to_it() {
+    let dir = scratch_dir("directory_diagnostics");
+    fs::write(dir.join("main.egg"), "push 1;\npush $;\n").unwrap();
+
+    let (ok, out) = cli(&[dir.to_str().unwrap()]);
+    assert!(!ok);
+    assert!(out.contains(" --> main.egg:2:6\n"), "{}", out);
+}
+
+#[test]
+fn pedantic_stack_rejects_an_underflow_before_compiling() {
+    let dir = scratch_dir("pedantic_stack");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push 1;\nbbq;\nadd;\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    assert!(cli(&[path]).0);
+    let (ok, out) = cli(&["--pedantic-stack", path]);
+    assert!(!ok);
+    assert!(out.ends_with("Statement 3: this 'add' consumes 2 values but only 1 was available at this point\n\
+                           note: the stack depth was 1 before this statement\n"), "{}", out);
+    assert!(!out.contains("Bytecode"), "{}", out);
+}
+
+#[test]
+fn fix_confusables_rewrites_the_file_and_reports_each_fix() {
+    let dir = scratch_dir("fix_confusables");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push \u{FF08}1 \u{2013} 2\u{FF09};\n\u{200B}bbq;\n").unwrap();
+    let name = path.to_str().unwrap();
+
+    let (ok, out) = cli(&["--fix-confusables", name]);
+    assert!(ok, "{}", out);
+    let reports: Vec<_> = out.lines().take_while(|line| !line.starts_with("Tokens")).collect();
+    assert_eq!(
+        reports,
+        [
+            format!("{}:1:6: replaced U+FF08 FULLWIDTH LEFT PARENTHESIS with '('", name),
+            format!("{}:1:9: replaced U+2013 EN DASH with '-'", name),
+            format!("{}:1:12: replaced U+FF09 FULLWIDTH RIGHT PARENTHESIS with ')'", name),
+            format!("{}:2:1: removed U+200B ZERO WIDTH SPACE", name),
+        ]
+    );
+    assert_eq!(fs::read_to_string(&path).unwrap(), "push (1 - 2);\nbbq;\n");
+}
+
+#[test]
+fn each_limit_fails_just_over_and_passes_at_its_value() {
+    let dir = scratch_dir("limits");
+    let path = dir.join("main.egg");
+    // 12 bytes, 5 tokens, 4 AST nodes and 3 instructions.
+    fs::write(&path, "push 1 + 2;\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let limits = [
+        ("source-bytes", "source bytes", 12),
+        ("tokens", "tokens", 5),
+        ("ast-nodes", "AST nodes", 4),
+        ("instructions", "instructions", 3),
+    ];
+    for (name, shown, found) in limits {
+        let (ok, out) = cli(&["--limit", &format!("{}={}", name, found - 1), path]);
+        assert!(!ok, "{}", out);
+        let expected = format!("Program exceeds the limit of {} {} ({} found)", found - 1, shown, found);
+        assert_eq!(out.lines().last(), Some(expected.as_str()));
+
+        let (ok, out) = cli(&["--limit", &format!("{}={}", name, found), path]);
+        assert!(ok, "{}", out);
+    }
+}
+
+#[test]
+fn limits_prints_the_configured_values() {
+    let (ok, out) = cli(&["--limit", "AST-nodes=7", "--limits"]);
+    assert!(ok);
+    assert!(out.contains("AST nodes      7\n"), "{}", out);
+
+    let (ok, out) = cli(&["--limit", "nodes=7"]);
+    assert!(!ok);
+    assert_eq!(out, "Option --limit needs a value like tokens=1000\n");
+}
+
+#[test]
+fn progress_reports_each_phase_in_order() {
+    let dir = scratch_dir("progress");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push 1;\npush 2;\nadd;\n").unwrap();
+
+    let (ok, out) = cli(&["--progress", path.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    let progress: Vec<(f64, &str)> = out
+        .lines()
+        .filter_map(|line| line.strip_prefix('['))
+        .map(|line| {
+            let (time, step) = line.split_once("s] ").unwrap();
+            (time.trim().parse().unwrap(), step)
+        })
+        .collect();
+    assert!(progress.windows(2).all(|pair| pair[0].0 <= pair[1].0), "{}", out);
+
+    let phases: Vec<_> = progress.iter().map(|(_, step)| *step).filter(|step| !step.ends_with('%')).collect();
+    assert_eq!(phases, ["read", "lex", "parse", "compile", "write", "done"]);
+    for phase in ["lex", "parse"] {
+        let percents: Vec<usize> = progress
+            .iter()
+            .filter_map(|(_, step)| step.strip_prefix(phase)?.strip_prefix(' ')?.strip_suffix('%')?.parse().ok())
+            .collect();
+        assert!(percents.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", percents);
+        assert_eq!(percents.last(), Some(&100), "{}", out);
+        let first = progress.iter().position(|(_, step)| step.starts_with(&format!("{} ", phase))).unwrap();
+        assert_eq!(progress[first - 1].1, phase, "{}", out);
+    }
+
+    let (ok, out) = cli(&[path.to_str().unwrap()]);
+    assert!(ok);
+    assert!(!out.lines().any(|line| line.starts_with('[')), "{}", out);
+}
+
+#[test]
+fn every_broken_call_argument_is_reported() {
+    let dir = scratch_dir("broken_arguments");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push foo(bar(1,), baz(,));\n").unwrap();
+
+    let (ok, out) = cli(&[path.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(out.matches("Expected an expression").count(), 2, "{}", out);
+    let notes = "note: while parsing argument 1 of call to 'baz'\nnote: while parsing argument 2 of call to 'foo'\n";
+    assert!(out.ends_with(notes), "{}", out);
+}
+
+#[test]
+fn bracket_problem_is_the_only_diagnostic() {
+    let dir = scratch_dir("bracket_problem");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push 1 +;\npush (2;\n").unwrap();
+
+    let (ok, out) = cli(&[path.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(out.matches(" --> ").count(), 1, "{}", out);
+    assert!(out.contains("Unclosed '(' opened on line 2 column 6\n"), "{}", out);
+}
+
+#[test]
+fn bare_expressions_are_pushed_only_under_calc() {
+    let dir = scratch_dir("calc");
+    let path = dir.join("main.egg");
+    fs::write(&path, "2 + 2;\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let (ok, out) = cli(&["--calc", "--run", path]);
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("4"));
+
+    let (ok, out) = cli(&["--run", path]);
+    assert!(!ok);
+    assert!(out.starts_with("Tokens"), "{}", out);
+    assert!(out.contains("Unexpected integer on line 1 column 1\n"), "{}", out);
+}
+
+/// Writes a prelude defining `twice` and a main file hatching it.
+fn prelude_project(name: &str) -> (PathBuf, PathBuf) {
+    let dir = scratch_dir(name);
+    fs::write(dir.join("prelude.egg"), "build twice(n) {\n    push n * 2;\n}\n").unwrap();
+    fs::write(dir.join("main.egg"), "push twice(21);\n").unwrap();
+    (dir.join("prelude.egg"), dir.join("main.egg"))
+}
+
+#[test]
+fn prelude_builds_can_be_hatched_from_the_main_file() {
+    let (prelude, main) = prelude_project("prelude_build");
+    let (ok, out) = cli(&["--run", "--prelude", prelude.to_str().unwrap(), main.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("42"));
+
+    let (ok, out) = cli(&["--run", main.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(out.lines().last(), Some("Undefined function 'twice' on line 1 column 6"));
+}
+
+#[test]
+fn build_defined_in_a_prelude_and_the_main_file_names_both() {
+    let (prelude, main) = prelude_project("prelude_collision");
+    fs::write(&main, "push twice(21);\nbuild twice(n) {\n    push n * 3;\n}\n").unwrap();
+    let (prelude, main) = (prelude.to_str().unwrap(), main.to_str().unwrap());
+
+    let (ok, out) = cli(&["--prelude", prelude, main]);
+    assert!(!ok);
+    let expected = format!("Build 'twice' is defined twice, at {}:1:1 and at {}:2:1", prelude, main);
+    assert_eq!(out.lines().last(), Some(expected.as_str()));
+}
+
+#[test]
+fn override_build_replaces_a_prelude_build() {
+    let (prelude, main) = prelude_project("prelude_override");
+    fs::write(&main, "push twice(21);\noverride build twice(n) {\n    push n * 3;\n}\n").unwrap();
+    let (ok, out) = cli(&["--run", "--prelude", prelude.to_str().unwrap(), main.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("63"));
+}
+
+#[test]
+fn prelude_diagnostics_name_the_prelude() {
+    let (prelude, main) = prelude_project("prelude_diagnostics");
+    fs::write(&prelude, "push 1;\npush $;\n").unwrap();
+    let (ok, out) = cli(&["--prelude", prelude.to_str().unwrap(), main.to_str().unwrap()]);
+    assert!(!ok);
+    assert!(out.contains(&format!(" --> {}:2:6\n", prelude.display())), "{}", out);
+}
+
+#[test]
+fn default_preludes_come_from_the_environment() {
+    let (prelude, main) = prelude_project("prelude_environment");
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_parse"))
+            .env("EGGSEMBLY_PRELUDE", &prelude)
+            .args(args)
+            .arg(&main)
+            .output()
+            .unwrap();
+        (output.status.success(), String::from_utf8(output.stdout).unwrap())
+    };
+
+    let (ok, out) = run(&["--run"]);
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("42"));
+
+    let (ok, out) = run(&["--run", "--no-default-prelude"]);
+    assert!(!ok);
+    assert_eq!(out.lines().last(), Some("Undefined function 'twice' on line 1 column 6"));
+}
+
+#[test]
+fn annotated_output_echoes_the_expression_behind_each_instruction() {
+    let dir = scratch_dir("annotated");
+    let src = "build add1(n) {\n    push n + 1;\n}\npush -5;\npush add1(add1(1));\n";
+    fs::write(dir.join("main.egg"), format!("{}compare {{\n    push 1;\n}} fr {{\n    push -2;\n}}\n", src)).unwrap();
+
+    let (ok, out) = cli(&["--emit", "annotated", dir.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    let listing = &out[out.find("main.egg:1: ").expect(&out)..];
+    assert_eq!(
+        listing,
+        "\
+main.egg:1: build add1(n) {
+      14  Store(0)
+      15  Load(0)          ; from 'n + 1' (addition)
+      16  Push(1)          ; from 'n + 1' (addition)
+      17  Add              ; from 'n + 1' (addition)
+      18  Return
+main.egg:4: push -5;
+       0  Push(0)          ; from '-5' (negation)
+       1  Push(5)          ; from '-5' (negation)
+       2  Fox              ; from '-5' (negation)
+main.egg:5: push add1(add1(1));
+       3  Push(1)          ; from 'add1(1)' (call)
+       4  Hatch(14)        ; from 'add1(1)' (call)
+       5  Hatch(14)        ; from 'add1(add1(1))' (call)
+main.egg:6: compare {
+       6  Compare
+       7  JumpUnless(10)
+       8  Push(1)
+       9  Jump(13)
+      10  Push(0)          ; from '-2' (negation)
+      11  Push(2)          ; from '-2' (negation)
+      12  Fox              ; from '-2' (negation)
+(end of program)
+      13  Axe
+"
+    );
+}
+
+/// Runs the command line with `input` piped to stdin.
+fn cli_stdin(args: &[&str], input: &str) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_parse"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn stdin_buffer_is_reported_under_the_given_name() {
+    let (ok, out) = cli_stdin(&["--stdin-filename", "src/main.egg"], "push 1;\npush $;\n");
+    assert!(!ok);
+    assert!(out.starts_with("Invalid character '$' on line 2 column 6\n --> src/main.egg:2:6\n"), "{}", out);
+
+    let (ok, out) = cli_stdin(&["--run", "--stdin-filename", "src/main.egg"], "push 3;\npush 4;\nadd;\n");
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("7"));
+}
+
+#[test]
+fn fixing_confusables_in_a_stdin_buffer_writes_no_file() {
+    let dir = scratch_dir("stdin_confusables");
+    let name = dir.join("main.egg");
+    let args = ["--fix-confusables", "--run", "--stdin-filename", name.to_str().unwrap()];
+    let (ok, out) = cli_stdin(&args, "push \u{2212}1;\n");
+    assert!(ok, "{}", out);
+    assert!(out.starts_with(&format!("{}:1:6: replaced U+2212 MINUS SIGN with '-'\n", name.display())), "{}", out);
+    assert_eq!(out.lines().last(), Some("-1"));
+    assert!(!name.exists());
+}
+
+#[test]
+fn todos_warn_at_compile_time_and_deny_todo_makes_them_errors() {
+    let dir = scratch_dir("todos");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push 1;\ntodo;\ntodo \"finish this\";\n").unwrap();
+    let path = path.to_str().unwrap();
+    let sites = |level: &str| {
+        format!(
+            "{0}: not yet implemented at {1}:2:1\n{0}: not yet implemented: finish this at {1}:3:1\n",
+            level, path
+        )
+    };
+
+    let (ok, out) = cli(&["--run", path]);
+    assert!(!ok);
+    assert!(out.contains(&sites("warning")), "{}", out);
+    assert_eq!(out.lines().last(), Some("Runtime error: not yet implemented at instruction 1"));
+
+    let (ok, out) = cli(&["--deny", "todo", "--run", path]);
+    assert!(!ok);
+    assert!(out.ends_with(&sites("error")), "{}", out);
+}
+
+#[test]
+fn run_prints_the_top_of_the_stack_or_the_runtime_error() {
+    let dir = scratch_dir("run_output");
+    let ok_path = dir.join("ok.egg");
+    fs::write(&ok_path, "push 1;\npush 2 * 21;\n").unwrap();
+    let (ok, out) = cli(&["--run", ok_path.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    assert_eq!(out.lines().last(), Some("42"));
+
+    let underflow = dir.join("underflow.egg");
+    fs::write(&underflow, "push 1;\nadd;\n").unwrap();
+    let (ok, out) = cli(&["--run", underflow.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(out.lines().last(), Some("Runtime error: Stack underflow at instruction 1"));
+}
+
+#[test]
+fn bbq_mode_chooses_how_characters_are_written() {
+    let dir = scratch_dir("bbq_mode");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push \"\";\npush 233;\nbbq;\nadd;\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    for (args, expected) in [
+        (&["--run", path][..], "\u{E9}"),
+        (&["--run", "--bbq-mode", "unicode", path], "\u{E9}"),
+        (&["--run", "--bbq-mode", "chicken-compat", path], "&#233;"),
+    ] {
+        let (ok, out) = cli(args);
+        assert!(ok, "{}", out);
+        assert_eq!(out.lines().last(), Some(expected), "{:?}", args);
+    }
+
+    let byte_args = ["--run", "--bbq-mode", "byte", path];
+    let output = Command::new(env!("CARGO_BIN_EXE_parse")).args(byte_args).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.ends_with(b"\n\xE9\n"), "{:?}", output.stdout);
+
+    let (ok, out) = cli(&["--run", "--bbq-mode", "ascii", path]);
+    assert!(!ok);
+    assert_eq!(out, "Unknown bbq mode ascii\n");
+}
+
+#[test]
+fn report_size_breaks_counts_down_by_function_and_line() {
+    let dir = scratch_dir("report_size");
+    fs::write(dir.join("main.egg"), "build sq(n) {\n    push n * n;\n}\npush 3; push 4;\nadd;\npush sq(2);\n").unwrap();
+    let (ok, out) = cli(&["--report-size", dir.to_str().unwrap()]);
+    assert!(ok, "{}", out);
+    let breakdown = [
+        "By function:",
+        "    (top level)      6",
+        "    sq               5",
+        "By line:",
+        "    main.egg:1       5",
+        "    main.egg:4       2",
+        "    main.egg:5       1",
+        "    main.egg:6       2",
+    ];
+    assert!(out.contains(&breakdown.join("\n")), "{}", out);
+}
+
+#[test]
+fn stats_report_what_parsed_and_then_the_error() {
+    let dir = scratch_dir("stats_partial");
+    let path = dir.join("main.egg");
+    fs::write(&path, "push 1;\npush (2;\npush 3;\n").unwrap();
+    let (ok, out) = cli(&["stats", path.to_str().unwrap()]);
+    assert!(!ok);
+    assert!(out.starts_with("Tokens:               10\n"), "{}", out);
+    assert!(out.contains("Parse errors:         1 (counts cover the statements before them)\n"), "{}", out);
+    assert!(out.contains("Expected ')', got ';' on line 2 column 8\n"), "{}", out);
+}
+
+#[test]
+fn source_bytes_are_limited_before_reading_the_input_whole() {
+    let dir = scratch_dir("source_bytes");
+    let big = dir.join("big.egg");
+    fs::write(&big, "push 1;\n".repeat(1 << 17)).unwrap();
+    let (ok, out) = cli(&["--limit", "source-bytes=100", big.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(out, "Program exceeds the limit of 100 source bytes (1048576 found)\n");
+
+    // Stdin has no length up front, so reading stops one byte past the limit.
+    let args = ["--limit", "source-bytes=10", "--stdin-filename", "main.egg"];
+    let (ok, out) = cli_stdin(&args, &"push 1;\n".repeat(100));
+    assert!(!ok);
+    assert_eq!(out, "Program exceeds the limit of 10 source bytes (11 found)\n");
+
+    // Preludes count toward the same total.
+    let prelude = dir.join("prelude.egg");
+    fs::write(&prelude, "push 1;\n").unwrap();
+    let main = dir.join("main.egg");
+    fs::write(&main, "push 2;\n").unwrap();
+    let args = ["--limit", "source-bytes=15", "--prelude", prelude.to_str().unwrap(), main.to_str().unwrap()];
+    let (ok, out) = cli(&args);
+    assert!(!ok);
+    assert_eq!(out, "Program exceeds the limit of 15 source bytes (16 found)\n");
+}
@@ -0,0 +1,435 @@
+use parse::{
+    compiler::{self, Code, CompileOptions, CompilePhases, SizeReport, StackUnderflow},
+    lexer::{Lexer, Token},
+    parser::{parse_source, Expr, Parser, Stmt},
+    vm::{Value, Vm},
+    Compiler,
+};
+
+fn parse(src: &str) -> Stmt {
+    parse_source(src).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn compile(src: &str) -> Vec<Code> {
+    Compiler::new().compile(&parse(src)).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn bump_pushes(stmt: &mut Stmt) {
+    match stmt {
+        Stmt::StmtSeq(seq) => seq.iter_mut().for_each(bump_pushes),
+        Stmt::Push(Expr::Int(n)) => *n += 1,
+        _ => {}
+    }
+}
+
+#[test]
+fn passes_run_before_code_generation_and_are_timed() {
+    let mut phases = CompilePhases::default();
+    phases.add_pass("bump", bump_pushes);
+    phases.add_pass("bump again", bump_pushes);
+    let mut ast = parse("push 1; add;");
+    let code = Compiler::new().compile_with_phases(&mut ast, &mut phases).unwrap();
+    assert_eq!(code, [Code::Push(3), Code::Add]);
+    assert_eq!(ast, parse("push 3; add;"));
+
+    let timings: Vec<_> = phases.timings().iter().map(|pass| (pass.name.as_str(), pass.nodes)).collect();
+    assert_eq!(timings, [("bump", 4), ("bump again", 4)]);
+}
+
+#[test]
+fn pick_and_peck_operands_are_pushed_first() {
+    assert_eq!(compile("pick 2; peck; peck 0;"), [Code::Push(2), Code::Pick, Code::Peck, Code::Push(0), Code::Peck]);
+}
+
+#[test]
+fn appending_parsed_statements_matches_compiling_the_whole_program() {
+    let src = "let x = 2; hatch double(x); build double(n) { push n * 2; } push 1; push 1; compare { push 3; } fr { push 4; }";
+    let mut compiler = Compiler::new();
+    for stmt in Parser::new(Lexer::new(src)) {
+        compiler.append(&stmt.unwrap()).unwrap();
+    }
+    assert_eq!(compiler.finish().unwrap(), compile(src));
+}
+
+/// Encodes code the way Chicken does, one line per instruction holding as
+/// many `chicken` words as its opcode.
+fn chicken_source(code: &[Code]) -> String {
+    let opcode = |code: &Code| match code {
+        Code::Push(n) => usize::try_from(*n).unwrap() + 10,
+        code => usize::from(code.info().chicken_opcode.unwrap()),
+    };
+    code.iter().map(|code| vec!["chicken"; opcode(code)].join(" ") + "\n").collect()
+}
+
+#[test]
+fn size_report_counts_the_words_chicken_would_emit() {
+    let code = compile("push 3; push 4; add; bbq; pick 0; axe;");
+    let report = SizeReport::new(&code);
+    assert_eq!(report.instructions, 7);
+    assert_eq!(report.by_mnemonic, [("axe", 1), ("add", 1), ("pick", 1), ("bbq", 1), ("push", 3)]);
+    assert_eq!(report.chicken_words, Some(chicken_source(&code).split_whitespace().count()));
+    assert_eq!(report.chicken_words, Some(13 + 14 + 2 + 9 + 10 + 6));
+}
+
+#[test]
+fn size_report_has_no_word_count_without_a_chicken_encoding() {
+    assert_eq!(SizeReport::new(&compile("push 1; push 2; push 1 < 2;")).chicken_words, None);
+    assert_eq!(SizeReport::new(&[Code::Push(-1)]).chicken_words, None);
+}
+
+#[test]
+fn size_report_breaks_counts_down_by_function_and_line() {
+    let Stmt::StmtSeq(stmts) = parse("build sq(n) {\n  push n * n;\n}\npush 3; push 4;\nadd;\npush sq(2);") else {
+        unreachable!()
+    };
+    let mut compiler = Compiler::new();
+    for stmt in &stmts {
+        compiler.append(stmt).unwrap();
+    }
+    let (code, origins) = compiler.finish_with_origins().unwrap();
+    let lines = [("main.egg", 1), ("main.egg", 4), ("main.egg", 4), ("main.egg", 5), ("main.egg", 6)];
+    let report = SizeReport::with_origins(&code, &origins, &stmts, &lines);
+
+    assert_eq!(report.instructions, 11);
+    assert_eq!(report.by_function, [("(top level)".to_owned(), 6), ("sq".to_owned(), 5)]);
+    let by_line: Vec<_> = report.by_line.iter().map(|(file, line, count)| (file.as_str(), *line, *count)).collect();
+    assert_eq!(by_line, [("main.egg", 1, 5), ("main.egg", 4, 2), ("main.egg", 5, 1), ("main.egg", 6, 2)]);
+    assert!(report.to_string().contains("By function:\n    (top level)      6\n    sq               5\n"));
+    assert!(report.to_string().contains("By line:\n    main.egg:1       5\n    main.egg:4       2\n"));
+}
+
+#[test]
+fn size_report_without_origins_has_no_breakdowns() {
+    let report = SizeReport::new(&compile("push 1;"));
+    assert!(report.by_function.is_empty() && report.by_line.is_empty());
+    assert!(!report.to_string().contains("By "));
+}
+
+fn check_balance(src: &str) -> Result<(), StackUnderflow> {
+    let Stmt::StmtSeq(stmts) = parse(src) else { unreachable!() };
+    compiler::check_stack_balance(&stmts, &CompileOptions::default())
+}
+
+#[test]
+fn balanced_program_passes_the_stack_check() {
+    assert!(check_balance("push 1; push 2; add; push 3; rooster; bbq;").is_ok());
+}
+
+#[test]
+fn stack_check_reports_the_first_underflow() {
+    let err = check_balance("push 1; bbq; add; add; push 5;").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Statement 3: this 'add' consumes 2 values but only 1 was available at this point\n\
+         note: the stack depth was 1 before this statement"
+    );
+}
+
+#[test]
+fn stack_check_stops_at_axe() {
+    assert!(check_balance("push 1; axe; add;").is_ok());
+}
+
+#[test]
+fn stack_check_applies_a_builds_net_effect_at_each_hatch() {
+    // `shrink` adds the two values below it, so each call leaves one fewer.
+    let err = check_balance("build shrink() { add; }\npush 1; push 2;\nhatch shrink();\nhatch shrink();").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Statement 5: this 'hatch' consumes 2 values but only 1 was available at this point\n\
+         note: the stack depth was 1 before this statement"
+    );
+    assert!(check_balance("push 1; push 2; push 3; hatch shrink(); hatch shrink(); build shrink() { add; }").is_ok());
+}
+
+#[test]
+fn stack_check_counts_arguments_and_results_of_calls() {
+    let src = "build drop(a, b) { push a; } push 1; push drop(2, 3); add; push drop(4, 5); add; add;";
+    let err = check_balance(src).unwrap_err();
+    assert_eq!((err.statement, err.mnemonic, err.available, err.depth_before), (7, "add", 1, 1));
+    assert!(check_balance("build two() { push 1; push 2; } hatch two(); add;").is_ok());
+}
+
+#[test]
+fn stack_check_stops_at_recursive_builds() {
+    let src = "build f(n) { push n; push 0; compare { push 0; } fr { push f(n - 1); add; } } push f(3); add;";
+    assert!(check_balance(src).is_ok());
+}
+
+fn chicken_words(code: &[Code]) -> usize {
+    SizeReport::new(code).chicken_words.unwrap()
+}
+
+#[test]
+fn chicken_size_rebuilds_large_constants_from_small_ones() {
+    for (n, smaller) in [(1000, true), (9973, true), (0, false), (1, false), (12, false), (100, true)] {
+        let plain = vec![Code::Push(n)];
+        let optimized = compiler::optimize_chicken_size(plain.clone(), 10_000);
+        let mut vm = Vm::new(optimized.clone());
+        assert_eq!(vm.run(), Ok(()), "{}", n);
+        assert_eq!(vm.stack(), [Value::Int(n)], "{}: {:?}", n, optimized);
+        if smaller {
+            assert!(chicken_words(&optimized) < chicken_words(&plain), "{}: {:?}", n, optimized);
+        } else {
+            assert_eq!(optimized, plain);
+        }
+    }
+}
+
+#[test]
+fn chicken_size_search_stays_within_its_budget_for_huge_constants() {
+    // A prime near 10^18 has no divisor below 10^9, and i64::MAX's largest
+    // trial divisors square past i64::MAX; neither may run unbounded.
+    for n in [999_999_999_999_999_989, i64::MAX] {
+        let optimized = compiler::optimize_chicken_size(vec![Code::Push(n)], 10_000);
+        let mut vm = Vm::new(optimized.clone());
+        assert_eq!(vm.run(), Ok(()), "{}", n);
+        assert_eq!(vm.stack(), [Value::Int(n)], "{}: {:?}", n, optimized);
+    }
+}
+
+#[test]
+fn chicken_size_without_budget_keeps_plain_pushes() {
+    let plain = vec![Code::Push(1000), Code::Push(9973), Code::Add];
+    assert_eq!(compiler::optimize_chicken_size(plain.clone(), 0), plain);
+}
+
+#[test]
+fn chicken_size_option_applies_to_compiled_programs() {
+    let options = CompileOptions { chicken_size_budget: Some(10_000), ..CompileOptions::default() };
+    let code = Compiler::with_options(options).compile(&parse("push 1000; push 9973; add;")).unwrap();
+    assert!(chicken_words(&code) < chicken_words(&compile("push 1000; push 9973; add;")));
+    let mut vm = Vm::new(code);
+    assert_eq!(vm.run(), Ok(()));
+    assert_eq!(vm.stack(), [Value::Int(10973)]);
+}
+
+#[test]
+fn origins_map_linked_code_back_to_appended_statements() {
+    let Stmt::StmtSeq(stmts) = parse("build f(n) { push n; } push -5; push f(1);") else { unreachable!() };
+    let mut compiler = Compiler::new();
+    for stmt in &stmts {
+        compiler.append(stmt).unwrap();
+    }
+    let (code, origins) = compiler.finish_with_origins().unwrap();
+    assert_eq!(code, compile("build f(n) { push n; } push -5; push f(1);"));
+    assert_eq!(
+        code.iter().cloned().zip(origins).collect::<Vec<_>>(),
+        [
+            (Code::Push(0), Some(1)),
+            (Code::Push(5), Some(1)),
+            (Code::Fox, Some(1)),
+            (Code::Push(1), Some(2)),
+            (Code::Hatch(6), Some(2)),
+            (Code::Axe, None),
+            (Code::Store(0), Some(0)),
+            (Code::Load(0), Some(0)),
+            (Code::Return, Some(0)),
+        ]
+    );
+}
+
+#[test]
+fn spans_tag_each_instruction_with_its_innermost_expression() {
+    let spanned = |src, options| {
+        let mut compiler = Compiler::with_options(options);
+        compiler.append(&parse(src)).unwrap();
+        let (code, _, spans) = compiler.finish_with_spans().unwrap();
+        let spans = spans.into_iter().map(|span| span.map(|expr| (expr.span.start, expr.span.end, expr.what)));
+        code.into_iter().zip(spans).collect::<Vec<_>>()
+    };
+    let (sum, negation) = (Some((5, 11, "addition")), Some((9, 11, "negation")));
+    assert_eq!(
+        spanned("push 1 + -2;", CompileOptions::default()),
+        [
+            (Code::Push(1), sum),
+            (Code::Push(0), negation),
+            (Code::Push(2), negation),
+            (Code::Fox, negation),
+            (Code::Add, sum),
+        ]
+    );
+    assert!(spanned("push 1; let x = 2;", CompileOptions::default()).iter().all(|(_, span)| span.is_none()));
+
+    // Code rebuilding a constant keeps the span of the push it replaces.
+    let options = CompileOptions { chicken_size_budget: Some(10_000), ..CompileOptions::default() };
+    let code = spanned("push 1000 * 2;", options);
+    assert!(code.len() > 3, "{:?}", code);
+    assert!(code.iter().all(|(_, span)| *span == Some((5, 13, "multiplication"))), "{:?}", code);
+}
+
+fn run(src: &str) -> Vec<Value> {
+    let mut vm = Vm::new(compile(src));
+    vm.run().unwrap_or_else(|err| panic!("{}", err));
+    vm.stack().to_vec()
+}
+
+#[test]
+fn let_stores_to_a_slot_that_later_uses_load() {
+    let sum = (Expr::int(3) + Expr::int(4)).spanning(8, 13);
+    assert_eq!(parse("let x = 3 + 4;"), Stmt::seq(vec![Stmt::assign("x", sum)]));
+    assert_eq!(
+        compile("let x = 3 + 4; let y = 1; push x; push y;"),
+        [
+            Code::Push(3),
+            Code::Push(4),
+            Code::Add,
+            Code::Store(0),
+            Code::Push(1),
+            Code::Store(1),
+            Code::Load(0),
+            Code::Load(1),
+        ]
+    );
+    assert_eq!(run("let x = 3 + 4; push x * x;"), [Value::Int(49)]);
+}
+
+#[test]
+fn using_a_variable_before_its_let_names_it() {
+    let err = Compiler::new().compile(&parse("push 1;\npush 2 + x;\nlet x = 1;")).unwrap_err();
+    assert_eq!(err, compiler::CompileError { message: "Undefined variable 'x'".to_owned(), line: 2, col: 10 });
+    assert_eq!(err.to_string(), "Undefined variable 'x' on line 2 column 10");
+}
+
+#[test]
+fn reassigning_a_variable_reuses_its_slot() {
+    let code = compile("let x = 1; let y = 2; let x = x + y; push x;");
+    assert_eq!(code.iter().filter(|code| matches!(code, Code::Store(0))).count(), 2);
+    assert!(!code.contains(&Code::Store(2)));
+    assert_eq!(run("let x = 1; let y = 2; let x = x + y; push x;"), [Value::Int(3)]);
+}
+
+#[test]
+fn build_parameters_shadow_outer_variables() {
+    assert_eq!(run("let x = 5; build f(x) { push x * 10; } push f(2); push x;"), [Value::Int(20), Value::Int(5)]);
+    let err = Compiler::new().compile(&parse("let y = 5; build f(x) { push y; } push f(2);")).unwrap_err();
+    assert_eq!(err.message, "Undefined variable 'y'");
+}
+
+#[test]
+fn string_concatenation_round_trips_to_the_vm() {
+    let src = "push \"a\" + \"b\";";
+    let tokens: Vec<_> = Lexer::new(src).into_iter().map(Result::unwrap).collect();
+    let (a, b) = (Token::String("a".to_owned()), Token::String("b".to_owned()));
+    assert_eq!(tokens, [Token::Push, a, Token::Plus, b, Token::Semi]);
+    assert_eq!(parse(src), Stmt::seq(vec![Stmt::push((Expr::string("a") + Expr::string("b")).spanning(5, 14))]));
+    assert_eq!(compile(src), [Code::PushString("a".to_owned()), Code::PushString("b".to_owned()), Code::Add]);
+    assert_eq!(run(src), [Value::Str("ab".into())]);
+}
+
+#[test]
+fn empty_and_escaped_strings_compile_to_their_text() {
+    assert_eq!(compile("push \"\";"), [Code::PushString(String::new())]);
+    assert_eq!(compile(r#"push "say \"hi\"\n";"#), [Code::PushString("say \"hi\"\n".to_owned())]);
+    assert_eq!(run(r#"push "" + "\"" + 1;"#), [Value::Str("\"1".into())]);
+}
+
+#[test]
+fn strings_with_multiply_or_divide_are_errors_at_the_operator() {
+    for op in ["*", "/"] {
+        let src = format!("push \"ab\" {} \"c\";", op);
+        let err = Parser::new(Lexer::new(&src)).parse().unwrap_err();
+        assert_eq!(err.message, format!("Strings can't be used with '{}'", op));
+        assert_eq!((err.span.start, err.span.end), (10, 11));
+    }
+}
+
+#[test]
+fn built_functions_run_with_their_arguments() {
+    assert_eq!(run("build sub(a, b) { push a - b; } push sub(10, 3);"), [Value::Int(7)]);
+    assert_eq!(run("build seven() { push 7; } push seven() + 1;"), [Value::Int(8)]);
+}
+
+#[test]
+fn functions_can_be_called_before_their_build() {
+    assert_eq!(run("push twice(21);\nbuild twice(n) { push n * 2; }"), [Value::Int(42)]);
+    assert_eq!(run("hatch twice(4);\nbuild twice(n) { push n * 2; }"), [Value::Int(8)]);
+}
+
+#[test]
+fn override_build_replaces_the_earlier_definition() {
+    let src = "build f(n) { push n; }\npush f(1);\noverride build f(n) { push n * 10; }\npush f(2);";
+    assert_eq!(run(src), [Value::Int(10), Value::Int(20)]);
+    // The arity checked at each call is the override's.
+    let src = "build f(n) { push n; } override build f(a, b) { push a + b; } push f(1, 2);";
+    assert_eq!(run(src), [Value::Int(3)]);
+
+    let err = Compiler::new().compile(&parse("build f() { push 1; }\nbuild f() { push 2; }")).unwrap_err();
+    assert_eq!(err.message, "Function 'f' is already defined");
+    assert_eq!((err.line, err.col), (2, 1));
+}
+
+#[test]
+fn calls_with_the_wrong_number_of_arguments_are_errors() {
+    let compile_err = |src| Compiler::new().compile(&parse(src)).unwrap_err();
+    let err = compile_err("build f(a, b) { push a; }\npush 1;\npush f(1);");
+    assert_eq!(err.message, "'f' takes 2 arguments but 1 was given");
+    assert_eq!((err.line, err.col), (3, 6));
+    let err = compile_err("build f(a) { push a; } push f(1, 2, 3);");
+    assert_eq!(err.message, "'f' takes 1 argument but 3 were given");
+    let err = compile_err("push 1;\nhatch g(1);");
+    assert_eq!(err.message, "Undefined function 'g'");
+    assert_eq!((err.line, err.col), (2, 7));
+}
+
+#[test]
+fn nested_calls_pass_results_as_arguments() {
+    let builds = "build f(a, b) { push a * 10 + b; } build g(n) { push n + 1; }";
+    let src = format!("{} push f(g(1), 2); push f(g(g(0)), f(0, 3));", builds);
+    assert_eq!(run(&src), [Value::Int(22), Value::Int(23)]);
+}
+
+#[test]
+fn functions_can_recurse() {
+    let src = "
+build fact(n) {
+    push n < 2;
+    push 1;
+    compare {
+        push 1;
+    } fr {
+        push n * fact(n - 1);
+    }
+}
+push fact(10);";
+    assert_eq!(run(src), [Value::Int(3_628_800)]);
+}
+
+#[test]
+fn program_built_without_source_text_compiles_and_runs() {
+    let program = Stmt::seq(vec![
+        Stmt::func("square", &["x"], vec![Stmt::push(Expr::var("x") * Expr::var("x"))]),
+        Stmt::assign("n", Expr::int(3) + Expr::int(4)),
+        Stmt::push(Expr::call("square", vec![Expr::var("n")]) - -Expr::int(1)),
+    ]);
+    let code = Compiler::with_options(CompileOptions::default()).compile(&program).unwrap();
+    assert_eq!(code, compile("build square(x) { push x * x; } let n = 3 + 4; push square(n) - -1;"));
+
+    let mut vm = Vm::new(code);
+    assert_eq!(vm.run(), Ok(()));
+    assert_eq!(vm.output(), Some(&Value::Int(50)));
+}
+
+#[test]
+fn errors_in_built_programs_say_generated_code() {
+    let err = Compiler::new().compile(&Stmt::push(Expr::var("missing"))).unwrap_err();
+    assert_eq!((err.line, err.col), (0, 0));
+    assert_eq!(err.to_string(), "Undefined variable 'missing' in generated code");
+
+    let program = Stmt::seq(vec![Stmt::func("f", &[], Vec::new()), Stmt::push(Expr::call("f", vec![Expr::int(1)]))]);
+    let err = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(err.to_string(), "'f' takes 0 arguments but 1 was given in generated code");
+}
+
+#[test]
+fn operators_without_an_instruction_are_errors_in_built_programs() {
+    let (one, two) = (Box::new(Expr::int(1)), Box::new(Expr::int(2)));
+    let span = Default::default();
+    let binary = Expr::BinOp { op: Token::Comma, left: one.clone(), right: two, span };
+    let err = Compiler::new().compile(&Stmt::push(binary)).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported operator ',' in generated code");
+
+    let unary = Expr::UnOp { op: Token::Mul, operand: one, span };
+    let err = Compiler::new().compile(&Stmt::push(unary)).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported unary operator '*' in generated code");
+}
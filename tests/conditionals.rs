@@ -0,0 +1,66 @@
+use parse::{
+    compiler::{self, Code, CompileOptions, StackUnderflow},
+    parser::{parse_source, Stmt},
+    Compiler,
+};
+
+fn compile(src: &str, options: CompileOptions) -> Vec<Code> {
+    let ast = parse_source(src).unwrap_or_else(|err| panic!("{}", err));
+    Compiler::with_options(options).compile(&ast).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn check_balance(src: &str) -> Result<(), StackUnderflow> {
+    let Stmt::StmtSeq(stmts) = parse_source(src).unwrap_or_else(|err| panic!("{}", err)) else { unreachable!() };
+    compiler::check_stack_balance(&stmts, &CompileOptions::default())
+}
+
+const NESTED: &str = "push 1; push 1; compare { push 2; push 3; compare { push 4; } fr { push 5; } } fr { push 6; }";
+
+#[test]
+fn nested_jumps_land_on_their_branches() {
+    use Code::*;
+    assert_eq!(
+        compile(NESTED, CompileOptions::default()),
+        [
+            Push(1), Push(1), Compare, JumpUnless(12),
+            Push(2), Push(3), Compare, JumpUnless(10), Push(4), Jump(11), Push(5), Jump(13),
+            Push(6),
+        ]
+    );
+}
+
+#[test]
+fn jumps_follow_chicken_size_rewrites() {
+    let options = CompileOptions { chicken_size_budget: Some(1000), ..CompileOptions::default() };
+    let code = compile("push 0; push 1000; compare { push 9973; } fr { push 1000; }", options);
+    let jumps: Vec<_> = code
+        .iter()
+        .filter_map(|code| match code {
+            Code::Jump(target) | Code::JumpUnless(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    let else_start = code.iter().rposition(|code| matches!(code, Code::Jump(_))).unwrap() + 1;
+    assert_eq!(jumps, [else_start, code.len()]);
+}
+
+#[test]
+fn every_branch_is_checked_for_underflow() {
+    let then_branch = check_balance("push 1; push 1; compare { add; }").unwrap_err();
+    assert_eq!((then_branch.statement, then_branch.mnemonic, then_branch.available), (3, "add", 0));
+
+    let else_branch = check_balance("push 1; push 2; compare { push 3; } fr { bbq; }").unwrap_err();
+    assert_eq!((else_branch.statement, else_branch.mnemonic, else_branch.available), (3, "bbq", 0));
+}
+
+#[test]
+fn the_next_statement_starts_from_the_shallowest_branch() {
+    assert!(check_balance("push 1; push 1; compare { push 2; } fr { push 2; push 3; } bbq;").is_ok());
+    let err = check_balance("push 1; push 1; compare { push 2; } fr { push 2; push 3; } add;").unwrap_err();
+    assert_eq!((err.statement, err.available, err.depth_before), (4, 1, 1));
+}
+
+#[test]
+fn a_branch_that_stops_leaves_the_depth_to_the_other() {
+    assert!(check_balance("push 1; push 1; compare { axe; } fr { push 2; push 3; } add;").is_ok());
+}
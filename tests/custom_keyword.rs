@@ -0,0 +1,93 @@
+//! Embedder-defined statements, as in `examples/custom_keyword.rs`: `beep n;`
+//! and `boop;` lowered to calls of host functions of the same names.
+
+use parse::{
+    compiler::{Code, CompileError, CompileOptions, Compiler},
+    error::ParseError,
+    lexer::{CustomKeywordId, Lexer, LexerOptions, Token},
+    parser::{Parser, ParserOptions, Stmt},
+    source::SourceFile,
+    vm::{Value, Vm},
+    Expr,
+};
+
+const BEEP: CustomKeywordId = CustomKeywordId(0);
+const BOOP: CustomKeywordId = CustomKeywordId(1);
+
+fn parse_custom(id: CustomKeywordId, parser: &mut Parser) -> Result<Stmt, ParseError> {
+    let args = if parser.lexer().lookahead == Some(Token::Semi) {
+        Vec::new()
+    } else {
+        vec![parser.parse_expr()?]
+    };
+    Ok(Stmt::Custom { id, args, line: 0, col: 0 })
+}
+
+fn lower_custom(id: CustomKeywordId) -> Vec<Code> {
+    match id {
+        BEEP => vec![Code::CallFunc("beep".to_owned())],
+        BOOP => vec![Code::CallFunc("boop".to_owned())],
+        _ => unreachable!(),
+    }
+}
+
+/// Replaces the frequency on top of the stack with its negation.
+fn beep(stack: &mut Vec<Value>) -> Result<(), String> {
+    match stack.pop() {
+        Some(Value::Int(hz)) => stack.push(Value::Int(-hz)),
+        _ => return Err("beep needs a frequency".to_owned()),
+    }
+    Ok(())
+}
+
+fn boop(stack: &mut Vec<Value>) -> Result<(), String> {
+    stack.push(Value::Int(7));
+    Ok(())
+}
+
+fn parse(src: &str) -> Stmt {
+    let options = LexerOptions::default().extra_keyword("beep", BEEP).extra_keyword("boop", BOOP);
+    let lexer = Lexer::with_options(SourceFile::new(src), options);
+    Parser::with_options(lexer, ParserOptions::default().on_custom_stmt(parse_custom))
+        .parse()
+        .unwrap_or_else(|err| panic!("{}", err))
+}
+
+#[test]
+fn custom_statements_parse_with_their_position() {
+    let ast = parse("push 1;\n  beep 440 * 2; boop;");
+    assert_eq!(
+        ast,
+        Stmt::seq(vec![
+            Stmt::push(Expr::int(1)),
+            Stmt::Custom { id: BEEP, args: vec![(Expr::int(440) * Expr::int(2)).spanning(15, 22)], line: 2, col: 3 },
+            Stmt::Custom { id: BOOP, args: Vec::new(), line: 2, col: 17 },
+        ])
+    );
+}
+
+#[test]
+fn custom_statements_run_their_host_functions() {
+    let ast = parse("push 1; beep 440 * 2; boop;");
+    let options = CompileOptions { lower_custom: Some(lower_custom), ..CompileOptions::default() };
+    let code = Compiler::with_options(options).compile(&ast).unwrap();
+
+    let mut vm = Vm::new(code);
+    vm.define("beep", beep);
+    vm.define("boop", boop);
+    assert_eq!(vm.run(), Ok(()));
+    assert_eq!(vm.stack(), [Value::Int(1), Value::Int(-880), Value::Int(7)]);
+}
+
+#[test]
+fn custom_statement_without_a_lowering_is_a_compile_error() {
+    let err = Compiler::new().compile(&parse("push 1;\nbeep 2;")).unwrap_err();
+    let message = "No lowering registered for custom statement CustomKeywordId(0)".to_owned();
+    assert_eq!(err, CompileError { message, line: 2, col: 1 });
+}
+
+#[test]
+fn custom_keywords_are_errors_without_a_statement_parser() {
+    let lexer = Lexer::with_options(SourceFile::new("beep 2;"), LexerOptions::default().extra_keyword("beep", BEEP));
+    assert_eq!(Parser::new(lexer).parse().unwrap_err().message, "Unexpected custom keyword");
+}
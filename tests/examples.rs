@@ -0,0 +1,41 @@
+//! Runs every program listed in `examples/manifest.txt` through the command
+//! line and checks what it prints.
+
+use std::{path::Path, process::Command};
+
+/// What each example leaves on top of the stack.
+const EXPECTED: &[(&str, &str)] =
+    &[("hello.egg", "Hi"), ("arithmetic.egg", "23"), ("stack.egg", "14"), ("fibonacci.egg", "55")];
+
+#[test]
+fn every_example_runs() {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let manifest = include_str!("../examples/manifest.txt");
+    for name in manifest.lines().filter_map(|line| line.split_once('\t')).map(|(name, _)| name) {
+        let expected = match EXPECTED.iter().find(|(example, _)| *example == name) {
+            Some((_, expected)) => expected,
+            None => panic!("no expected output for {}", name),
+        };
+        let output = Command::new(env!("CARGO_BIN_EXE_parse")).arg("--run").arg(examples.join(name)).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(output.status.success(), "{} failed:\n{}", name, stdout);
+        assert_eq!(stdout.lines().last(), Some(*expected), "{}", name);
+    }
+}
+
+#[test]
+fn every_expected_output_is_listed_in_the_manifest() {
+    let manifest = include_str!("../examples/manifest.txt");
+    for (name, _) in EXPECTED {
+        assert!(manifest.lines().any(|line| line.starts_with(&format!("{}\t", name))), "{}", name);
+    }
+}
+
+#[test]
+fn examples_command_lists_the_manifest() {
+    let output = Command::new(env!("CARGO_BIN_EXE_parse")).arg("examples").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let names: Vec<_> = stdout.lines().filter_map(|line| line.split_whitespace().next()).collect();
+    assert_eq!(names, ["hello.egg", "arithmetic.egg", "stack.egg", "fibonacci.egg"]);
+    assert!(stdout.contains("fibonacci.egg    A recursive build"), "{}", stdout);
+}
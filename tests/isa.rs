@@ -0,0 +1,29 @@
+use std::collections::HashSet;
+
+use parse::compiler::{self, Code};
+
+#[test]
+fn isa_lists_every_instruction_once() {
+    let isa = compiler::isa();
+    let mnemonics: HashSet<_> = isa.iter().map(|info| info.mnemonic).collect();
+    assert_eq!(mnemonics.len(), isa.len());
+    for code in [Code::Push(7), Code::PushString("egg".to_owned()), Code::Trap("later".to_owned()), Code::Gt] {
+        assert!(mnemonics.contains(code.info().mnemonic), "{:?}", code);
+    }
+}
+
+#[test]
+fn chicken_opcodes_run_in_order() {
+    let opcodes: Vec<_> = compiler::isa().iter().filter_map(|info| info.chicken_opcode).collect();
+    assert_eq!(opcodes, (0..=10).collect::<Vec<u8>>());
+}
+
+#[test]
+fn markdown_and_json_cover_the_same_instructions() {
+    let markdown = compiler::isa_markdown();
+    let json = compiler::isa_json();
+    for info in compiler::isa() {
+        assert!(markdown.contains(&format!("|{}|", info.mnemonic)), "{}", info.mnemonic);
+        assert!(json.contains(&format!("\"mnemonic\": \"{}\"", info.mnemonic)), "{}", info.mnemonic);
+    }
+}
@@ -0,0 +1,227 @@
+use parse::{
+    error::{LexError, ParseError},
+    lexer::{fix_confusables, Lexer, Token, CONFUSABLES, INVISIBLES, KEYWORD_NAMES},
+    parser::{parse_source, Expr, Parser, Stmt},
+};
+
+fn lex(src: &str) -> Vec<Token> {
+    Lexer::new(src).into_iter().collect::<Result<_, _>>().unwrap_or_else(|err| panic!("{}", err))
+}
+
+#[test]
+fn line_comment_ends_at_a_lone_carriage_return() {
+    assert_eq!(lex("push 1; // one\rpush 2;\r"), lex("push 1;\npush 2;\n"));
+}
+
+#[test]
+fn line_comment_ends_at_crlf() {
+    assert_eq!(lex("push 1; // one\r\npush 2;"), lex("push 1; push 2;"));
+}
+
+#[test]
+fn buffered_tokens_replay_into_the_parser() {
+    let src = "push (1 + 2) * 3; pick 0;";
+    let mut lexer = Lexer::new(src);
+    let mut spans = Vec::new();
+    lexer.buffer(|tok, span| spans.push((tok.clone(), &src[span.start..span.end])));
+    assert_eq!(spans.len(), 12);
+    assert_eq!(spans[1], (Token::LParen, "("));
+    assert!(lexer.check_brackets().is_ok());
+    assert_eq!(Parser::new(lexer).parse(), parse_source(src));
+}
+
+#[test]
+fn bracket_check_leaves_a_lexing_error_to_the_parser() {
+    let mut lexer = Lexer::new("push (1 + $;");
+    assert!(lexer.check_brackets().is_ok());
+    let err = Parser::new(lexer).parse().unwrap_err();
+    assert_eq!(err.message, "Invalid character '$'");
+}
+
+#[test]
+fn block_comments_nest() {
+    assert_eq!(lex("push /* a /* b */ c */ 1;"), lex("push 1;"));
+    let err = lex_error("push 1; /* a /* b */");
+    assert_eq!(err.message, "Unterminated block comment starting");
+    assert_eq!((err.span.start, err.span.end), (8, 10));
+}
+
+#[test]
+fn comment_at_end_of_file_needs_no_newline() {
+    assert_eq!(lex("push 1; // done"), lex("push 1;"));
+    assert_eq!(lex("push 1; /* done */"), lex("push 1;"));
+    assert_eq!(lex("//"), []);
+}
+
+#[test]
+fn comment_between_push_and_its_expression() {
+    assert_eq!(lex("push // the answer\n 42;"), [Token::Push, Token::Int(42), Token::Semi]);
+    assert_eq!(lex("push /* the answer */ 42;"), [Token::Push, Token::Int(42), Token::Semi]);
+}
+
+#[test]
+fn single_slash_is_still_division() {
+    let ident = |name: &str| Token::Identifier(name.to_owned());
+    assert_eq!(lex("a / b"), [ident("a"), Token::Div, ident("b")]);
+    assert_eq!(lex("a/b"), [ident("a"), Token::Div, ident("b")]);
+    assert_eq!(lex("a / /* b */ c"), [ident("a"), Token::Div, ident("c")]);
+}
+
+#[test]
+fn positions_after_a_multi_line_comment() {
+    let err = lex_error("/* one\ntwo\n */ push $;");
+    assert_eq!((err.line, err.col), (3, 10));
+    assert_eq!((err.span.start, err.span.end), (20, 21));
+}
+
+fn lex_error(src: &str) -> LexError {
+    Lexer::new(src).into_iter().find_map(Result::err).unwrap_or_else(|| panic!("{:?} lexed", src))
+}
+
+#[test]
+fn each_confusable_is_named_with_its_ascii_counterpart() {
+    for &(ch, name, ascii) in CONFUSABLES.iter().filter(|(ch, _, _)| !ch.is_whitespace()) {
+        let err = lex_error(&format!("push 1 {} 2;", ch));
+        assert_eq!(err.message, format!("Invalid character U+{:04X} {}", ch as u32, name));
+        assert_eq!(err.notes, [format!("did you mean '{}'?", ascii)]);
+        assert_eq!((err.span.start, err.span.end), (7, 7 + ch.len_utf8()));
+    }
+}
+
+#[test]
+fn no_break_space_separates_tokens() {
+    assert_eq!(lex("push\u{A0}1;"), lex("push 1;"));
+}
+
+#[test]
+fn leading_byte_order_mark_is_skipped() {
+    let src = "\u{FEFF}push 1;\npush 2 + 3;";
+    assert_eq!(lex(src), lex("push 1;\npush 2 + 3;"));
+    // Spans stay byte offsets into the text as given, BOM included.
+    let sum = (Expr::int(2) + Expr::int(3)).spanning(16, 21);
+    assert_eq!(parse_source(src), Ok(Stmt::seq(vec![Stmt::push(Expr::int(1)), Stmt::push(sum)])));
+
+    let err = lex_error("\u{FEFF}push 1;\npush $;");
+    assert_eq!((err.line, err.col), (2, 6));
+    assert_eq!((err.span.start, err.span.end), (16, 17));
+    let err = lex_error("\u{FEFF}push $;");
+    assert_eq!((err.line, err.col), (1, 6));
+    assert_eq!((err.span.start, err.span.end), (8, 9));
+}
+
+#[test]
+fn invisible_character_between_tokens_is_named_with_its_span() {
+    let err = lex_error("push 1;\u{200B}push 2;");
+    assert_eq!(err.message, "Invisible character U+200B ZERO WIDTH SPACE");
+    assert_eq!(err.notes, ["remove it; --fix-confusables does this for you"]);
+    assert_eq!((err.line, err.col), (1, 8));
+    assert_eq!((err.span.start, err.span.end), (7, 10));
+
+    for &(ch, name) in INVISIBLES {
+        let err = lex_error(&format!("push 1;\npush{}2;", ch));
+        assert_eq!(err.message, format!("Invisible character U+{:04X} {}", ch as u32, name));
+        assert_eq!((err.line, err.col), (2, 5));
+        assert_eq!((err.span.start, err.span.end), (12, 12 + ch.len_utf8()));
+    }
+}
+
+#[test]
+fn fix_confusables_replaces_and_removes() {
+    let (fixed, fixes) = fix_confusables("push \u{2212}1;\u{200B} push \u{201C}a\u{201D};");
+    assert_eq!(fixed, "push -1; push \"a\";");
+    let fixes: Vec<_> = fixes.iter().map(|fix| (fix.offset, fix.name, fix.replacement)).collect();
+    assert_eq!(
+        fixes,
+        [
+            (5, "MINUS SIGN", Some('-')),
+            (10, "ZERO WIDTH SPACE", None),
+            (19, "LEFT DOUBLE QUOTATION MARK", Some('"')),
+            (23, "RIGHT DOUBLE QUOTATION MARK", Some('"')),
+        ]
+    );
+}
+
+#[test]
+fn string_literals_lex_without_separators() {
+    let string = |s: &str| Token::String(s.to_owned());
+    assert_eq!(lex(r#"push "";"#), [Token::Push, string(""), Token::Semi]);
+    assert_eq!(lex(r#""a""b""#), [string("a"), string("b")]);
+    assert_eq!(lex(r#""a" "b""#), [string("a"), string("b")]);
+    assert_eq!(lex(r#""a\"b\\c\n";"#), [string("a\"b\\c\n"), Token::Semi]);
+}
+
+#[test]
+fn unterminated_string_points_at_its_opening_quote() {
+    for src in [r#"push "abc"#, r#"push "a\"#, r#"push "a\"b"#] {
+        let err = lex_error(src);
+        assert_eq!(err.message, "Unterminated string starting", "{:?}", src);
+        assert_eq!(err.span.start, 5, "{:?}", src);
+    }
+}
+
+fn bracket_error(src: &str) -> ParseError {
+    Lexer::new(src).check_brackets().expect_err(src)
+}
+
+#[test]
+fn nested_unclosed_braces_report_the_innermost_opener() {
+    let err = bracket_error("build f(n) {\n    compare {\n        push n;\n");
+    assert_eq!((err.message.as_str(), err.line, err.col), ("Unclosed '{' opened", 2, 13));
+    assert!(err.labels.is_empty());
+}
+
+#[test]
+fn stray_closer_mid_file_is_reported_where_it_is() {
+    let err = bracket_error("push 1;\npush 2);\npush (3;");
+    assert_eq!((err.message.as_str(), err.line, err.col), ("No matching '(' for this ')'", 2, 7));
+    let err = bracket_error("push 1;\n}\npush 2;");
+    assert_eq!((err.message.as_str(), err.line, err.col), ("No matching '{' for this '}'", 2, 1));
+}
+
+#[test]
+fn interleaved_brackets_label_the_unclosed_opener() {
+    let err = bracket_error("push ( [ ) ];");
+    assert_eq!(err.message, "Mismatched ')', expected ']'");
+    assert_eq!((err.span.start, err.span.end), (9, 10));
+    assert_eq!(err.labels.len(), 1);
+    assert_eq!(err.labels[0].message, "unclosed '[' opened");
+    assert_eq!((err.labels[0].span.start, err.labels[0].line, err.labels[0].col), (7, 1, 8));
+}
+
+/// What each keyword lexes to. Run under both keyword tables, with and without
+/// the `phf` feature, this pins them to the same answers.
+const KEYWORD_TOKENS: &[(&str, Token)] = &[
+    ("let", Token::Let),
+    ("build", Token::Build),
+    ("override", Token::Override),
+    ("hatch", Token::Hatch),
+    ("push", Token::Push),
+    ("TOP", Token::Top),
+    ("axe", Token::Axe),
+    ("chicken", Token::Chicken),
+    ("add", Token::Add),
+    ("fox", Token::Fox),
+    ("rooster", Token::Rooster),
+    ("compare", Token::Cmp),
+    ("pick", Token::Pick),
+    ("peck", Token::Peck),
+    ("fr", Token::Fr),
+    ("bbq", Token::Bbq),
+    ("todo", Token::Todo),
+];
+
+#[test]
+fn every_keyword_lexes_to_its_token() {
+    let words: Vec<_> = KEYWORD_TOKENS.iter().map(|(word, _)| *word).collect();
+    assert_eq!(words, KEYWORD_NAMES);
+    for (word, token) in KEYWORD_TOKENS {
+        assert_eq!(lex(word), std::slice::from_ref(token), "{}", word);
+    }
+}
+
+#[test]
+fn near_keywords_are_identifiers() {
+    for word in ["Push", "PUSH", "top", "Top", "pushy", "pus", "le", "lett", "fr_", "_fr", "bbq2", "chickens", "addd"] {
+        assert_eq!(lex(word), [Token::Identifier(word.to_owned())], "{}", word);
+    }
+}
@@ -0,0 +1,105 @@
+//! Every combination of the lexer and parser switches, each checked to change
+//! what comes out.
+
+use parse::{
+    lexer::{CustomKeywordId, Lexer, LexerOptions, Token},
+    parser::{Parser, ParserOptions, Stmt},
+    source::SourceFile,
+    Expr,
+};
+
+#[test]
+fn lexer_options_matrix() {
+    const BEEP: CustomKeywordId = CustomKeywordId(0);
+    for newline_separators in [false, true] {
+        for case_insensitive_keywords in [false, true] {
+            for beep in [false, true] {
+                let mut options = LexerOptions::default()
+                    .newline_separators(newline_separators)
+                    .case_insensitive_keywords(case_insensitive_keywords);
+                if beep {
+                    options = options.extra_keyword("beep", BEEP);
+                }
+                let mut lexer = Lexer::with_options(SourceFile::new("BBQ\nbeep 1"), options);
+                let tokens: Vec<_> = lexer.into_iter().map(Result::unwrap).collect();
+
+                let bbq = if case_insensitive_keywords { Token::Bbq } else { Token::Identifier("BBQ".to_owned()) };
+                let mut expected = vec![bbq];
+                expected.extend(newline_separators.then_some(Token::Semi));
+                expected.push(if beep { Token::Custom(BEEP) } else { Token::Identifier("beep".to_owned()) });
+                expected.push(Token::Int(1));
+                expected.extend(newline_separators.then_some(Token::Semi));
+                let case = (newline_separators, case_insensitive_keywords, beep);
+                assert_eq!(tokens, expected, "newline separators, case-insensitive keywords, beep: {:?}", case);
+            }
+        }
+    }
+}
+
+#[test]
+fn parser_options_matrix() {
+    for char_literal_coercion in [false, true] {
+        for implicit_push in [false, true] {
+            let options =
+                ParserOptions::default().char_literal_coercion(char_literal_coercion).implicit_push(implicit_push);
+            let result = Parser::with_options(Lexer::new("\"a\" - 1;"), options).parse().map_err(|err| err.message);
+            let expected = match (char_literal_coercion, implicit_push) {
+                (_, false) => Err("Unexpected string".to_owned()),
+                (false, true) => Err("Strings can't be used with '-'".to_owned()),
+                (true, true) => Ok(Stmt::seq(vec![Stmt::push((Expr::int(97) - Expr::int(1)).spanning(0, 7))])),
+            };
+            assert_eq!(result, expected, "coercion {} implicit push {}", char_literal_coercion, implicit_push);
+        }
+    }
+}
+
+#[test]
+fn max_nesting_counts_each_open_parenthesis() {
+    for (max_nesting, ok) in [(1, false), (2, false), (3, true), (256, true)] {
+        let options = ParserOptions::default().max_nesting(max_nesting);
+        let result = Parser::with_options(Lexer::new("push ((1));"), options).parse();
+        assert_eq!(result.is_ok(), ok, "max_nesting {}", max_nesting);
+        if let Err(err) = result {
+            assert_eq!(err.message, format!("Expression nesting exceeds the limit of {}", max_nesting));
+        }
+    }
+}
+
+#[test]
+fn max_nesting_counts_each_operator_in_a_chain() {
+    for src in ["push 1 + 1 + 1;", "push 1 + 2 * 3 - 4;"] {
+        for (max_nesting, ok) in [(2, false), (3, true)] {
+            let options = ParserOptions::default().max_nesting(max_nesting);
+            let result = Parser::with_options(Lexer::new(src), options).parse();
+            assert_eq!(result.is_ok(), ok, "{:?} with max_nesting {}", src, max_nesting);
+        }
+    }
+}
+
+#[test]
+fn long_chains_and_deep_blocks_stop_at_the_limit() {
+    let chain = format!("push {};", vec!["1"; 200_000].join(" + "));
+    let err = Parser::new(Lexer::new(&chain)).parse().unwrap_err();
+    assert_eq!(err.message, "Expression nesting exceeds the limit of 256");
+    // At the 257th operand.
+    assert_eq!((err.line, err.col), (1, 6 + 256 * 4));
+
+    let blocks = format!("push 1; push 1; {}{}", "compare { ".repeat(100_000), "}".repeat(100_000));
+    let err = Parser::new(Lexer::new(&blocks)).parse().unwrap_err();
+    assert_eq!(err.message, "Block nesting exceeds the limit of 64");
+
+    let directives = format!("{}{}", "@if a\n".repeat(100_000), "@end\n".repeat(100_000));
+    let err = Parser::new(Lexer::new(&directives)).parse().unwrap_err();
+    assert_eq!((err.message.as_str(), err.line), ("Block nesting exceeds the limit of 64", 65));
+}
+
+#[test]
+fn max_block_nesting_is_separate_from_expression_nesting() {
+    let src = "push 1; push 1; compare { compare { push (1); } }";
+    let options = ParserOptions::default().max_block_nesting(1);
+    let err = Parser::with_options(Lexer::new(src), options).parse().unwrap_err();
+    assert_eq!(err.message, "Block nesting exceeds the limit of 1");
+
+    let options = ParserOptions::default().max_block_nesting(2).max_nesting(2);
+    assert!(Parser::with_options(Lexer::new(src), options).parse().is_ok());
+}
@@ -0,0 +1,365 @@
+use parse::{
+    error::ParseError,
+    lexer::{Lexer, Token, TokenKind},
+    parser::{parse_source, Assoc, Expr, Parser, ParserOptions, Stmt, BINARY_OPERATORS},
+    source::Span,
+};
+
+fn parse_with(src: &str, options: ParserOptions) -> Result<Stmt, ParseError> {
+    Parser::with_options(Lexer::new(src), options).parse()
+}
+
+fn parse(src: &str) -> Stmt {
+    parse_source(src).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn parse_err(src: &str) -> ParseError {
+    parse_source(src).expect_err(src)
+}
+
+fn coercing() -> ParserOptions {
+    ParserOptions::default().char_literal_coercion(true)
+}
+
+#[test]
+fn char_literal_before_bbq_is_its_code_point() {
+    let ast = parse_with("push \"H\"; bbq;", coercing()).unwrap();
+    assert_eq!(ast, Stmt::seq(vec![Stmt::push(Expr::int(72)), Stmt::Bbq]));
+}
+
+#[test]
+fn char_literal_in_arithmetic_is_its_code_point() {
+    let ast = parse_with("push \"a\" - 1; push -\"b\";", coercing()).unwrap();
+    let (difference, negation) = ((Expr::int(97) - Expr::int(1)).spanning(5, 12), (-Expr::int(98)).spanning(19, 23));
+    assert_eq!(ast, Stmt::seq(vec![Stmt::push(difference), Stmt::push(negation)]));
+}
+
+#[test]
+fn char_literal_stays_a_string_when_pushed_or_concatenated() {
+    let ast = parse_with("push \"H\"; push \"H\" + \"i\"; bbq;", coercing()).unwrap();
+    assert_eq!(
+        ast,
+        Stmt::seq(vec![
+            Stmt::push(Expr::string("H")),
+            Stmt::push((Expr::string("H") + Expr::string("i")).spanning(15, 24)),
+            Stmt::Bbq,
+        ])
+    );
+}
+
+#[test]
+fn longer_strings_are_not_coerced() {
+    let err = parse_with("push \"ab\" * 2;", coercing()).unwrap_err();
+    assert_eq!(err.message, "Only one-character strings can be used as numbers, but this one has 2 characters");
+    assert_eq!((err.span.start, err.span.end), (5, 9));
+
+    let err = parse_with("push \"ab\"; bbq;", coercing()).unwrap_err();
+    assert_eq!((err.span.start, err.span.end), (5, 9));
+}
+
+#[test]
+fn without_coercion_char_literals_are_strings() {
+    let ast = parse_with("push \"H\"; bbq;", ParserOptions::default()).unwrap();
+    assert_eq!(ast, Stmt::seq(vec![Stmt::push(Expr::string("H")), Stmt::Bbq]));
+    let err = parse_with("push \"a\" - 1;", ParserOptions::default()).unwrap_err();
+    assert_eq!(err.message, "Strings can't be used with '-'");
+}
+
+#[test]
+fn first_coercion_is_noted() {
+    let mut parser = Parser::with_options(Lexer::new("push 1;\npush \"H\"; bbq; push \"i\"; bbq;"), coercing());
+    assert!(parser.by_ref().all(|stmt| stmt.is_ok()));
+    assert_eq!(parser.notes(), ["\"H\" on line 2 column 6 is used as its character code 72"]);
+}
+
+#[test]
+fn pick_and_peck_take_constant_operands() {
+    assert_eq!(
+        parse("pick; pick 2; peck 1 + 2;"),
+        Stmt::seq(vec![Stmt::Pick(None), Stmt::Pick(Some(2)), Stmt::Peck(Some(3))])
+    );
+}
+
+#[test]
+fn pick_and_peck_operands_must_be_non_negative_constants() {
+    let err = parse_err("pick -1;");
+    assert_eq!(err.message, "Operand of pick must be a non-negative integer that fits in 32 bits");
+    assert_eq!((err.span.start, err.span.end), (5, 7));
+
+    let err = parse_err("let x = 1; peck x;");
+    assert_eq!(err.message, "Operand of peck must be a constant integer expression");
+    assert_eq!((err.line, err.col), (1, 17));
+}
+
+#[test]
+fn expecting_an_identifier_names_the_keyword_found() {
+    let err = parse_err("let push = 1;");
+    assert_eq!(err.message, "Expected identifier, got keyword 'push'");
+    assert_eq!((err.line, err.col, &*err.found), (1, 5, "push"));
+
+    let err = parse_err("build f(1) {}");
+    assert_eq!(err.message, "Expected identifier, got integer");
+}
+
+#[test]
+fn expect_kind_returns_the_token_it_consumes() {
+    let mut lexer = Lexer::new("name = 1");
+    assert_eq!(lexer.expect_kind(TokenKind::Identifier), Ok(Token::Identifier("name".to_owned())));
+    assert_eq!(lexer.expect_exact(&Token::Eq), Ok(()));
+    assert_eq!(lexer.expect_kind(TokenKind::Identifier).unwrap_err().message, "Expected identifier, got integer");
+}
+
+/// A parsed call to `name` on line 1, from `col` up to byte offset `end`.
+fn call_at(name: &str, args: Vec<Expr>, col: usize, end: usize) -> Expr {
+    Expr::FunctionCall { name: name.to_owned(), args, line: 1, col, span: Span { start: col - 1, end } }
+}
+
+#[test]
+fn calls_parse_after_names_and_parenthesized_names() {
+    assert_eq!(parse("push f();"), Stmt::seq(vec![Stmt::push(call_at("f", vec![], 6, 8))]));
+    assert_eq!(parse("push (f)(1);"), Stmt::seq(vec![Stmt::push(call_at("f", vec![Expr::int(1)], 6, 11))]));
+    let g = call_at("g", vec![Expr::int(2), Expr::int(3)], 11, 17);
+    assert_eq!(
+        parse("push f(1, g(2, 3)) + 4;"),
+        Stmt::seq(vec![Stmt::push((call_at("f", vec![Expr::int(1), g], 6, 18) + Expr::int(4)).spanning(5, 22))])
+    );
+}
+
+#[test]
+fn only_names_are_callable() {
+    for src in ["push (1)(2);", "push f(g(1))(2);", "push \"f\"(1);"] {
+        let err = parse_err(src);
+        assert_eq!((err.message.as_str(), err.col), ("Expression is not callable", 6), "{}", src);
+    }
+}
+
+#[test]
+fn a_missing_argument_is_an_error() {
+    let err = parse_err("push f(1,);");
+    assert_eq!(err.message, "Expected an expression, got ')'");
+    assert_eq!(err.col, 10);
+}
+
+#[test]
+fn truncated_constructs_name_what_was_expected_at_end_of_file() {
+    let cases = [
+        ("push 1 +", "Expected an expression after '+', found end of file", 7),
+        ("let x =", "Expected an expression after '=', found end of file", 6),
+        ("push foo(1,", "Expected an expression after ',', found end of file", 10),
+        ("push", "Expected an expression after keyword 'push', found end of file", 0),
+        ("push 1", "Expected ';' after integer, found end of file", 5),
+        ("let", "Expected identifier after keyword 'let', found end of file", 0),
+        ("compare { push 1; } fr", "Expected '{' after keyword 'fr', found end of file", 20),
+        ("compare {", "Missing '}' for the compare block", 0),
+    ];
+    for (src, message, start) in cases {
+        let err = parse_err(src);
+        assert_eq!((err.message.as_str(), err.span.start), (message, start), "{:?}", src);
+    }
+}
+
+const TRUNCATED_FIXTURE: &str = r#"let n = 10;
+build fib(n) {
+    push n < 2;
+    push 1;
+    compare {
+        push n;
+    } fr {
+        push fib(n - 1) + fib((n - 2));
+    }
+}
+push fib(n) * -3;
+push "done";
+pick 0;
+bbq;
+"#;
+
+#[test]
+fn every_truncation_fails_once_inside_the_file() {
+    let mut ends = Vec::new();
+    Lexer::new(TRUNCATED_FIXTURE).buffer(|_, span| ends.push(span.end));
+    assert!(parse_source(TRUNCATED_FIXTURE).is_ok());
+    for end in ends {
+        let src = &TRUNCATED_FIXTURE[..end];
+        let mut lexer = Lexer::new(src);
+        let results: Vec<_> = match lexer.check_brackets() {
+            Ok(()) => Parser::new(lexer).collect(),
+            Err(err) => vec![Err(err)],
+        };
+        let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+        assert!(errors.len() <= 1, "{:?}: {:?}", src, errors);
+        if let Some(err) = errors.first() {
+            assert!(err.span.start < err.span.end && err.span.end <= src.len(), "{:?}: {:?}", src, err);
+        } else {
+            assert!(src.ends_with(';') || src.ends_with('}'), "{:?} parsed", src);
+        }
+    }
+}
+
+fn pushed_expr(src: &str) -> Expr {
+    match parse(src) {
+        Stmt::StmtSeq(stmts) => match &stmts[..] {
+            [Stmt::Push(expr)] => expr.clone(),
+            stmts => panic!("{:?}", stmts),
+        },
+        stmt => panic!("{:?}", stmt),
+    }
+}
+
+/// `expr` with its spans cleared, to compare only its shape.
+fn shape(expr: Expr) -> Expr {
+    let span = Span::default();
+    match expr {
+        Expr::BinOp { op, left, right, .. } => {
+            Expr::BinOp { op, left: Box::new(shape(*left)), right: Box::new(shape(*right)), span }
+        }
+        Expr::UnOp { op, operand, .. } => Expr::UnOp { op, operand: Box::new(shape(*operand)), span },
+        Expr::FunctionCall { name, args, line, col, .. } => {
+            Expr::FunctionCall { name, args: args.into_iter().map(shape).collect(), line, col, span }
+        }
+        expr => expr,
+    }
+}
+
+#[test]
+fn chained_operators_group_by_the_operator_table() {
+    let int = Expr::int;
+    // Every binary operator must appear here, chained with itself and with the
+    // other operators of its precedence.
+    let cases = [
+        ("10 - 3 - 2", (int(10) - int(3)) - int(2), 5),
+        ("100 / 10 / 5", (int(100) / int(10)) / int(5), 2),
+        ("1 + 2 + 3", (int(1) + int(2)) + int(3), 6),
+        ("2 * 3 * 4", (int(2) * int(3)) * int(4), 24),
+        ("10 - 3 + 2", (int(10) - int(3)) + int(2), 9),
+        ("10 + 3 - 2", (int(10) + int(3)) - int(2), 11),
+        ("12 / 3 * 2", (int(12) / int(3)) * int(2), 8),
+        ("12 * 3 / 2", (int(12) * int(3)) / int(2), 18),
+        ("1 < 2 < 3", (int(1).lt(int(2))).lt(int(3)), 1),
+        ("3 > 2 > 1", (int(3).gt(int(2))).gt(int(1)), 0),
+        ("3 > 2 < 1", (int(3).gt(int(2))).lt(int(1)), 0),
+        ("1 + 2 * 3 - 4 / 2", (int(1) + int(2) * int(3)) - int(4) / int(2), 5),
+        ("1 < 2 + 3", int(1).lt(int(2) + int(3)), 1),
+        ("-2 * 3", -int(2) * int(3), -6),
+    ];
+    for (src, ast, value) in &cases {
+        let expr = pushed_expr(&format!("push {};", src));
+        assert_eq!(&shape(expr.clone()), ast, "{}", src);
+        assert_eq!(expr.eval_const(), Some(*value), "{}", src);
+    }
+    for op in BINARY_OPERATORS {
+        assert_eq!(op.assoc, Assoc::Left, "{}", op.token);
+        let symbol = format!(" {} ", op.token.to_string().trim_matches('\''));
+        let chained = cases.iter().any(|(src, _, _)| src.matches(&symbol).count() == 2);
+        assert!(chained, "no chained {} case", op.token);
+    }
+}
+
+fn parse_errors(src: &str) -> Vec<ParseError> {
+    Parser::new(Lexer::new(src)).filter_map(Result::err).collect()
+}
+
+#[test]
+fn broken_arguments_of_nested_calls_are_all_reported_with_their_call() {
+    let errors = parse_errors("push foo(bar(1,), baz(,)); push 2;");
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+    assert_eq!((errors[0].message.as_str(), errors[0].span.start), ("Expected an expression, got ')'", 15));
+    let notes = ["while parsing argument 2 of call to 'bar'", "while parsing argument 1 of call to 'foo'"];
+    assert_eq!(errors[0].notes, notes);
+    assert_eq!((errors[1].message.as_str(), errors[1].span.start), ("Expected an expression, got ','", 22));
+    let notes = ["while parsing argument 1 of call to 'baz'", "while parsing argument 2 of call to 'foo'"];
+    assert_eq!(errors[1].notes, notes);
+}
+
+#[test]
+fn argument_recovery_skips_nested_parentheses() {
+    let errors = parse_errors("push f(1 + (2 3), g(5)); push 1;");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].message, "Expected ')', got integer");
+
+    let errors = parse_errors("push f(g(1 +), 2 *);");
+    let messages: Vec<_> = errors.iter().map(|err| err.message.as_str()).collect();
+    assert_eq!(messages, ["Expected an expression, got ')'"; 2]);
+}
+
+#[test]
+fn argument_errors_precede_the_error_ending_their_statement() {
+    let errors = parse_errors("push f(,) push 1;");
+    let messages: Vec<_> = errors.iter().map(|err| err.message.as_str()).collect();
+    assert_eq!(messages, ["Expected an expression, got ','", "Expected ';', got keyword 'push'"]);
+}
+
+#[test]
+fn implicit_push_reads_bare_expressions_as_pushes() {
+    let implicit = ParserOptions::default().implicit_push(true);
+    for (src, expr) in [
+        ("2 + 2;", (Expr::int(2) + Expr::int(2)).spanning(0, 5)),
+        ("-1;", (-Expr::int(1)).spanning(0, 2)),
+        ("(1);", Expr::int(1)),
+        ("\"a\";", Expr::string("a")),
+        ("x * 3;", (Expr::Variable { name: "x".to_owned(), line: 1, col: 1 } * Expr::int(3)).spanning(0, 5)),
+    ] {
+        assert_eq!(parse_with(src, implicit.clone()), Ok(Stmt::seq(vec![Stmt::push(expr)])), "{}", src);
+        assert!(parse_with(src, ParserOptions::default()).is_err(), "{}", src);
+    }
+}
+
+#[test]
+fn implicit_push_leaves_keyword_statements_alone() {
+    let implicit = ParserOptions::default().implicit_push(true);
+    let src = "let x = 2; push x; bbq; x;";
+    let x = |col| Expr::Variable { name: "x".to_owned(), line: 1, col };
+    let expected = Stmt::seq(vec![Stmt::assign("x", Expr::int(2)), Stmt::push(x(17)), Stmt::Bbq, Stmt::push(x(25))]);
+    assert_eq!(parse_with(src, implicit.clone()), Ok(expected));
+    assert_eq!(parse_with("x = 3;", implicit).unwrap_err().message, "Expected ';', got '='");
+}
+
+#[test]
+fn todo_sites_are_listed_in_source_order() {
+    let mut parser = Parser::new(Lexer::new("push 1;\n  todo;\nbuild f() {\n    todo \"body\";\n}\n"));
+    assert!(parser.by_ref().all(|stmt| stmt.is_ok()));
+    let todos: Vec<_> = parser.todos().iter().map(|todo| (todo.line, todo.col, todo.message.as_deref())).collect();
+    assert_eq!(todos, [(2, 3, None), (4, 5, Some("body"))]);
+}
+
+#[test]
+fn errors_report_exact_positions() {
+    let cases = [
+        ("push 1;\n  push 2 # 3;", "Invalid character '#'", (2, 10), "#"),
+        ("push 1;\npush \"abc;\npush 2;", "Unterminated string starting", (2, 6), "\"abc;\npush 2;"),
+        ("push (1 + 2;\npush 3;", "Expected ')', got ';'", (1, 12), ";"),
+        ("push\n\t(1 * (2 + 3);", "Expected ')', got ';'", (2, 14), ";"),
+    ];
+    for (src, message, position, found) in cases {
+        let err = parse_err(src);
+        assert_eq!((err.message.as_str(), (err.line, err.col), &*err.found), (message, position, found), "{:?}", src);
+        assert_eq!(&src[err.span.start..err.span.end], found, "{:?}", src);
+    }
+}
+
+fn parser_for(src: &str) -> Parser<'_> {
+    Parser::new(Lexer::new(src))
+}
+
+#[test]
+fn parser_returned_from_a_helper_drives_its_own_lexer() {
+    let src = String::from("push 1;\npush 2 + 3;");
+    let mut parser = parser_for(&src);
+    assert_eq!(parser.lexer().lookahead, Some(Token::Push));
+    assert_eq!(parser.next(), Some(Ok(Stmt::push(Expr::int(1)))));
+    assert_eq!(parser.lexer().token_pos(), (2, 1));
+    assert_eq!(parser.next(), Some(Ok(Stmt::push((Expr::int(2) + Expr::int(3)).spanning(13, 18)))));
+    assert_eq!(parser.next(), None);
+    assert_eq!(parser_for(&src).parse(), Ok(parse(&src)));
+}
+
+#[test]
+fn lexer_is_primed_and_steps_one_token_at_a_time() {
+    let mut lexer = Lexer::new("push x;");
+    assert_eq!(lexer.lookahead, Some(Token::Push));
+    assert_eq!(lexer.expect_exact(&Token::Push), Ok(()));
+    assert_eq!(lexer.expect_kind(TokenKind::Identifier), Ok(Token::Identifier("x".to_owned())));
+    assert_eq!(lexer.expect_exact(&Token::Push).unwrap_err().message, "Expected keyword 'push', got ';'");
+    lexer.step_token();
+    assert_eq!(lexer.lookahead, None);
+}
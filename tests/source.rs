@@ -0,0 +1,148 @@
+use parse::{
+    lexer::LexerOptions,
+    parser::parse_source,
+    source::{SourceFile, Span, SNIPPET_WIDTH},
+};
+
+#[test]
+fn columns_count_chars_through_tabs_and_emoji() {
+    let file = SourceFile::new("\tpush 1;\n🥚🐔 push 2;");
+    assert_eq!(file.line_col(1), (1, 2));
+    let push = "\tpush 1;\n🥚🐔 ".len();
+    assert_eq!(file.line_col(push), (2, 4));
+    assert_eq!(file.offset(2, 4), Some(push));
+    assert_eq!(file.offset(2, 2), Some("\tpush 1;\n🥚".len()));
+}
+
+#[test]
+fn crlf_ends_one_line() {
+    let file = SourceFile::new("push 1;\r\npush 2;\r\n");
+    assert_eq!(file.line_count(), 3);
+    assert_eq!(file.line_text(1), "push 1;");
+    assert_eq!(file.line_span(1), Span { start: 0, end: 7 });
+    assert_eq!(file.line_col(9), (2, 1));
+    assert_eq!(file.offset(2, 1), Some(9));
+}
+
+#[test]
+fn last_line_without_a_newline_runs_to_the_end() {
+    let file = SourceFile::new("push 1;\npush 2;");
+    assert_eq!(file.line_count(), 2);
+    assert_eq!(file.line_text(2), "push 2;");
+    assert_eq!(file.line_col(15), (2, 8));
+    assert_eq!(file.line_col(100), (2, 8));
+    assert_eq!(file.offset(2, 8), Some(15));
+    assert_eq!(file.offset(2, 9), None);
+    assert_eq!(file.offset(3, 1), None);
+}
+
+#[test]
+fn line_col_and_offset_round_trip() {
+    let text = "\tpush 1;\r\n🥚\rpush 2;\n\npush \"é\";";
+    let file = SourceFile::new(text);
+    // The `\n` of a CRLF is inside the terminator, so only positions before it
+    // come back.
+    for (offset, _) in text.char_indices().filter(|&(offset, ch)| !(ch == '\n' && text[..offset].ends_with('\r'))) {
+        let (line, col) = file.line_col(offset);
+        assert_eq!(file.offset(line, col), Some(offset), "{}", offset);
+    }
+}
+
+#[test]
+fn diagnostics_report_the_same_positions() {
+    let src = "push \"🥚\";\r\n\tpush $;";
+    let err = parse_source(src).unwrap_err();
+    assert_eq!((err.line, err.col), SourceFile::new(src).line_col(err.span.start));
+    assert_eq!((err.line, err.col), (2, 7));
+}
+
+/// The same program with each kind of line ending, and with them mixed.
+fn line_ending_fixtures(lines: &[&str]) -> Vec<(&'static str, String)> {
+    let mixed: String = lines
+        .iter()
+        .zip(["\n", "\r\n", "\r"].iter().cycle())
+        .map(|(line, end)| format!("{}{}", line, end))
+        .collect();
+    vec![("LF", lines.join("\n")), ("CRLF", lines.join("\r\n")), ("CR", lines.join("\r")), ("mixed", mixed)]
+}
+
+#[test]
+fn every_line_ending_parses_the_same() {
+    let lines = ["push 1; // one", "push 2;", "/* three", "*/ add;"];
+    let expected = parse_source(&lines.join("\n")).unwrap();
+    for (name, src) in line_ending_fixtures(&lines) {
+        assert_eq!(parse_source(&src).as_ref(), Ok(&expected), "{}", name);
+        assert_eq!(SourceFile::new(&src).line_text(4), "*/ add;", "{}", name);
+    }
+}
+
+#[test]
+fn every_line_ending_counts_lines_for_errors() {
+    let lines = ["push 1;", "", "push 2;", "  push $;"];
+    for (name, src) in line_ending_fixtures(&lines) {
+        let err = parse_source(&src).unwrap_err();
+        assert_eq!((err.line, err.col), (4, 8), "{}", name);
+    }
+}
+
+/// The char a rendered caret points at, and the rendered line's length.
+fn caret_target(rendered: &str) -> (char, usize) {
+    let (text, caret) = rendered.split_once('\n').unwrap();
+    let line: Vec<char> = text.chars().collect();
+    (line[caret.chars().count() - 1], line.len())
+}
+
+#[test]
+fn long_lines_are_windowed_around_the_caret() {
+    // 2000 chars, each 2 or 3 bytes, with a marker at the start, middle and end.
+    let mut chars: Vec<char> = "é€".repeat(1000).chars().collect();
+    (chars[0], chars[1000], chars[1999]) = ('S', 'M', 'E');
+    let line: String = chars.into_iter().collect();
+    let file = SourceFile::new(&line);
+    for marker in ['S', 'M', 'E'] {
+        let offset = line.find(marker).unwrap();
+        let rendered = file.render_caret(offset, false, 4);
+        let (target, len) = caret_target(&rendered);
+        assert_eq!(target, marker, "{}", rendered);
+        assert!(len <= SNIPPET_WIDTH + 2, "{}", len);
+    }
+    assert!(file.render_caret(line.find('S').unwrap(), false, 4).starts_with("S€é"));
+    assert!(file.render_caret(line.find('M').unwrap(), false, 4).starts_with('…'));
+    assert!(file.render_caret(line.find('E').unwrap(), false, 4).split('\n').next().unwrap().ends_with("éE"));
+}
+
+#[test]
+fn full_lines_are_not_windowed() {
+    let line = "push 1 + ".repeat(100) + "$;";
+    let rendered = SourceFile::new(&line).render_caret(line.len() - 2, true, 4);
+    assert_eq!(caret_target(&rendered), ('$', line.chars().count()));
+}
+
+#[test]
+fn tabs_in_mixed_indentation_expand_to_their_stops() {
+    let src = " \t  push $;\n\t \tpush 1;";
+    let file = SourceFile::new(src);
+    let dollar = src.find('$').unwrap();
+    assert_eq!(file.line_col(dollar), (1, 10));
+    assert_eq!(file.render_caret(dollar, false, 4), "      push $;\n           ^");
+    assert_eq!(file.render_caret(dollar, false, 8), "          push $;\n               ^");
+
+    let push = src.rfind("push").unwrap();
+    assert_eq!(file.line_col(push), (2, 4));
+    assert_eq!(file.render_caret(push, false, 4), "        push 1;\n        ^");
+}
+
+#[test]
+fn caret_right_after_a_tab_points_at_the_next_stop() {
+    let src = "push\t$;";
+    let file = SourceFile::new(src);
+    assert_eq!(file.render_caret(5, false, 4), "push    $;\n        ^");
+    assert_eq!(file.render_caret(5, false, 3), "push  $;\n      ^");
+    assert_eq!(file.render_caret(4, false, 4), "push    $;\n    ^");
+
+    let err = parse_source(src).unwrap_err();
+    assert_eq!((err.line, err.col), (1, 6));
+    let report = err.report(&file, &LexerOptions::default().tab_width(8));
+    assert!(report.contains("on line 1 column 6\n"), "{}", report);
+    assert!(report.contains("\npush    $;\n        ^\n"), "{}", report);
+}
@@ -0,0 +1,66 @@
+use parse::stats::{BuildStats, Stats};
+
+const PROGRAM: &str = "let x = 2;
+build sq(n) {
+    push n * n;
+}
+build sign(n) {
+    push n < 0;
+    push 1;
+    compare {
+        push -1;
+    } fr {
+        push n > 0;
+    }
+}
+
+push sq(x) + 1;
+@if debug
+bbq;
+@end
+";
+
+#[test]
+fn json_snapshot() {
+    assert_eq!(
+        Stats::collect(PROGRAM).to_json(),
+        r#"{
+  "tokens": 60,
+  "lines": 17,
+  "statements": {"@if": 1, "bbq": 1, "build": 2, "compare": 1, "let": 1, "push": 6},
+  "expr_nodes": 17,
+  "max_expr_depth": 3,
+  "literals": {"0": 2, "1": 3, "2": 1},
+  "builds": [{"name": "sq", "size": 4, "complexity": 1}, {"name": "sign", "size": 14, "complexity": 2}],
+  "instructions": 27,
+  "instructions_per_line": 1.59,
+  "errors": []
+}
+"#
+    );
+}
+
+#[test]
+fn program_that_does_not_compile_has_no_instructions() {
+    let stats = Stats::collect("push undefined(1);");
+    assert_eq!((stats.instructions, stats.instructions_per_line()), (0, 0.0));
+}
+
+#[test]
+fn statements_before_a_parse_error_are_counted() {
+    let stats = Stats::collect("build f(n) {\n    push n; fr;\n}\npush f(2);\npush (1;\npush 3;");
+    let errors: Vec<_> = stats.errors.iter().map(ToString::to_string).collect();
+    assert_eq!(errors, ["Expected ')', got ';' on line 5 column 8"]);
+    assert_eq!(stats.statements.values().sum::<usize>(), 4);
+    assert_eq!(stats.builds, [BuildStats { name: "f".to_owned(), size: 3, complexity: 2 }]);
+    assert_eq!(stats.instructions, 7);
+    assert!(stats.to_json().ends_with("\"errors\": [\"Expected ')', got ';' on line 5 column 8\"]\n}\n"));
+}
+
+#[test]
+fn lexing_errors_are_reported_after_the_tokens_before_them() {
+    let stats = Stats::collect("push 1;\npush 2 $ 3;");
+    assert_eq!(stats.tokens, 5);
+    assert_eq!(stats.statements.get("push"), Some(&1));
+    assert_eq!(stats.errors[0].message, "Invalid character '$'");
+}
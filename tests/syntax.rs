@@ -0,0 +1,23 @@
+use parse::{
+    lexer::{COMMENTS, KEYWORD_NAMES},
+    syntax,
+};
+
+#[test]
+fn json_lists_every_keyword_in_order() {
+    let json = syntax::syntax_json();
+    let words: Vec<_> = json.lines().filter_map(|line| line.trim().strip_prefix("{\"word\": \"")?.split('"').next()).collect();
+    assert_eq!(words, KEYWORD_NAMES);
+}
+
+#[test]
+fn json_lists_the_comments_the_lexer_skips() {
+    let json = syntax::syntax_json();
+    for (open, close) in COMMENTS {
+        let entry = match close {
+            Some(close) => format!("{{\"kind\": \"block\", \"open\": {:?}, \"close\": {:?}}}", open, close),
+            None => format!("{{\"kind\": \"line\", \"open\": {:?}}}", open),
+        };
+        assert!(json.contains(&entry), "{}", entry);
+    }
+}
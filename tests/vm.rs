@@ -0,0 +1,276 @@
+use std::{collections::HashMap, rc::Rc};
+
+use parse::{
+    compiler::{self, Code, StackEffect},
+    parser::parse_source,
+    vm::{BbqMode, ExecCode, RuntimeError, Value, Vm, VmOptions},
+    Compiler,
+};
+
+fn run(code: Vec<Code>) -> Result<Vec<Value>, RuntimeError> {
+    let mut vm = Vm::new(code);
+    vm.run()?;
+    Ok(vm.stack().to_vec())
+}
+
+fn run_source(src: &str) -> Result<Vec<Value>, RuntimeError> {
+    let ast = parse_source(src).unwrap_or_else(|err| panic!("{}", err));
+    run(Compiler::new().compile(&ast).unwrap_or_else(|err| panic!("{}", err)))
+}
+
+fn ints(values: &[i64]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(values.iter().copied().map(Value::Int).collect())
+}
+
+#[test]
+fn load_reads_what_store_wrote() {
+    let code = vec![Code::Push(4), Code::Store(1), Code::Load(1), Code::Load(1)];
+    assert_eq!(run(code), Ok(vec![Value::Int(4), Value::Int(4)]));
+}
+
+#[test]
+fn loading_a_slot_never_stored_fails_below_and_above_the_highest_store() {
+    let below = vec![Code::Push(4), Code::Store(2), Code::Load(0)];
+    assert_eq!(run(below), Err(RuntimeError { message: "Slot 0 is empty".to_owned(), index: 2 }));
+
+    let above = vec![Code::Push(4), Code::Store(2), Code::Load(3)];
+    assert_eq!(run(above), Err(RuntimeError { message: "Slot 3 is empty".to_owned(), index: 2 }));
+}
+
+fn link_error(code: &[Code]) -> Option<RuntimeError> {
+    ExecCode::link(code, &HashMap::new()).err()
+}
+
+#[test]
+fn link_rejects_calls_and_jumps_past_the_end() {
+    let error = |message: &str, index| Some(RuntimeError { message: message.to_owned(), index });
+    assert_eq!(link_error(&[Code::Push(1), Code::Hatch(2)]), error("Call to 2 is past the end of the program", 1));
+    assert_eq!(link_error(&[Code::Jump(2)]), error("Jump to 2 is past the end of the program", 0));
+    assert_eq!(link_error(&[Code::Push(1), Code::JumpUnless(3)]), error("Jump to 3 is past the end of the program", 1));
+    assert_eq!(link_error(&[Code::Label(0)]), error("Label 0 was never linked", 0));
+}
+
+#[test]
+fn link_accepts_a_jump_to_the_end() {
+    assert_eq!(link_error(&[Code::Push(0), Code::JumpUnless(3), Code::Push(1)]), None);
+}
+
+#[test]
+fn link_rejects_unknown_host_functions() {
+    let error = link_error(&[Code::CallFunc("beep".to_owned())]);
+    assert_eq!(error, Some(RuntimeError { message: "Undefined function 'beep'".to_owned(), index: 0 }));
+}
+
+#[test]
+fn malformed_code_fails_before_running_anything() {
+    let mut vm = Vm::new(vec![Code::Push(1), Code::Hatch(7)]);
+    assert!(vm.run().is_err());
+    assert_eq!(vm.stack(), []);
+}
+
+fn push_two(stack: &mut Vec<Value>) -> Result<(), String> {
+    stack.push(Value::Int(2));
+    Ok(())
+}
+
+#[test]
+fn define_relinks_the_code() {
+    let mut vm = Vm::new(vec![Code::CallFunc("two".to_owned())]);
+    assert!(vm.run().is_err());
+    vm.define("two", push_two);
+    assert_eq!(vm.run(), Ok(()));
+    assert_eq!(vm.stack(), [Value::Int(2)]);
+}
+
+#[test]
+fn pick_and_peck_address_the_stack_from_the_bottom() {
+    assert_eq!(run_source("push 5; push 6; pick 0;"), ints(&[5, 6, 5]));
+    assert_eq!(run_source("push 5; push 6; push 9; peck 0;"), ints(&[9, 6]));
+    let err = run_source("push 5; pick 1;").unwrap_err();
+    assert_eq!(err, RuntimeError { message: "Address 1 is outside the stack".to_owned(), index: 2 });
+}
+
+#[test]
+fn comparison_operators_push_one_or_zero() {
+    for (src, expected) in [
+        ("push 1 < 2;", 1),
+        ("push 2 < 1;", 0),
+        ("push 2 < 2;", 0),
+        ("push 2 > 1;", 1),
+        ("push 1 > 2;", 0),
+        ("push 2 > 2;", 0),
+        ("push 2; push 2; compare;", 1),
+        ("push 2; push 3; compare;", 0),
+        ("push 1; push 1.0; compare;", 1),
+        ("push 1.5; push 1; compare;", 0),
+        ("push 1; push \"1\"; compare;", 0),
+        ("push 1.5 < 2;", 1),
+        ("push 2 > 1.5;", 1),
+        ("push -1 < 0 - 0;", 1),
+    ] {
+        assert_eq!(run_source(src), ints(&[expected]), "{}", src);
+    }
+}
+
+#[test]
+fn comparing_large_integers_is_exact() {
+    // Both sides round to the same f64, so a float comparison would call them equal.
+    let (a, b) = (9_007_199_254_740_992_i64, 9_007_199_254_740_993_i64);
+    assert_eq!(run_source(&format!("push {} < {};", a, b)), ints(&[1]));
+    assert_eq!(run_source(&format!("push {} > {};", b, a)), ints(&[1]));
+    assert_eq!(run_source(&format!("push {0} > {0};", i64::MAX)), ints(&[0]));
+}
+
+#[test]
+fn strings_have_no_order() {
+    let code = vec![Code::PushString("a".to_owned()), Code::PushString("b".to_owned()), Code::Lt];
+    assert_eq!(run(code), Err(RuntimeError { message: "Can't compare a string by size".to_owned(), index: 2 }));
+    assert_eq!(run_source("push \"a\"; push \"a\"; compare;"), ints(&[1]));
+}
+
+/// Runs `instruction` on operands pushed in order, after a `push 1` that keeps
+/// the stack from being empty.
+fn run_on(operands: Vec<Code>, instruction: &Code) -> Result<Vec<Value>, String> {
+    let code: Vec<_> = [Code::Push(1)].into_iter().chain(operands).chain([instruction.clone()]).collect();
+    run(code).map_err(|err| err.message)
+}
+
+#[test]
+fn every_instruction_has_defined_behavior_on_string_operands() {
+    let text = || Code::PushString("ab".to_owned());
+    let string = |text: &str| Value::Str(text.into());
+    let error = |message: &str| Err(message.to_owned());
+    let cannot = |verb: &str| {
+        ["a string and an integer", "an integer and a string", "a string and a string"]
+            .map(|operands| Err(format!("Can't {} {}", verb, operands)))
+    };
+    let int_and = |values: [Value; 3]| values.map(|value| Ok(vec![Value::Int(1), value]));
+    let bad_address = || error("Address must be an integer, got a string");
+    let bad_offset = || error("Jump offset must be an integer, got a string");
+
+    // For each instruction popping two values: the result with a string below
+    // an integer, with an integer below a string, and with two strings.
+    let binary = [
+        (Code::Add, int_and([string("ab2"), string("2ab"), string("abab")])),
+        (Code::Fox, cannot("subtract")),
+        (Code::Rooster, cannot("multiply")),
+        (Code::Div, cannot("divide")),
+        (Code::Compare, int_and([Value::Int(0), Value::Int(0), Value::Int(1)])),
+        (Code::Lt, [(); 3].map(|_| error("Can't compare a string by size"))),
+        (Code::Gt, [(); 3].map(|_| error("Can't compare a string by size"))),
+        (Code::Peck, [Ok(vec![string("ab")]), bad_address(), bad_address()]),
+        (Code::Fr, [Ok(vec![Value::Int(1)]), bad_offset(), bad_offset()]),
+    ];
+    // Instructions popping one value, given a string.
+    let unary = [
+        (Code::Pick, bad_address()),
+        (Code::Bbq, error("bbq needs an integer, got a string")),
+        (Code::Store(0), Ok(vec![Value::Int(1)])),
+        (Code::JumpUnless(2), Ok(vec![Value::Int(1)])),
+    ];
+
+    for (instruction, [below, above, both]) in &binary {
+        // The integer operand is 0 where it is an address or offset, and 2
+        // elsewhere so div has something to divide by.
+        let int = || Code::Push(if matches!(instruction, Code::Peck | Code::Fr) { 0 } else { 2 });
+        assert_eq!(&run_on(vec![text(), int()], instruction), below, "{:?}", instruction);
+        assert_eq!(&run_on(vec![int(), text()], instruction), above, "{:?}", instruction);
+        assert_eq!(&run_on(vec![text(), text()], instruction), both, "{:?}", instruction);
+    }
+    for (instruction, expected) in &unary {
+        assert_eq!(&run_on(vec![text()], instruction), expected, "{:?}", instruction);
+    }
+
+    let covered: Vec<_> = binary.iter().map(|(code, _)| code.info().mnemonic).collect();
+    let covered = [covered, unary.iter().map(|(code, _)| code.info().mnemonic).collect()].concat();
+    for info in compiler::isa() {
+        if let StackEffect::Fixed { pops: 1.., .. } = info.effect {
+            assert!(covered.contains(&info.mnemonic), "{} is not covered", info.mnemonic);
+        }
+    }
+}
+
+#[test]
+fn todo_traps_with_its_message() {
+    let err = run_source("push 1;\ntodo \"finish this\";\npush 2;").unwrap_err();
+    assert_eq!(err, RuntimeError { message: "not yet implemented: finish this".to_owned(), index: 1 });
+    let err = run_source("push 1; push 2; todo;").unwrap_err();
+    assert_eq!(err, RuntimeError { message: "not yet implemented".to_owned(), index: 2 });
+}
+
+#[test]
+fn source_programs_leave_their_results_on_the_stack() {
+    assert_eq!(run_source("push 1; push 2 + 3 * 4; push 10 - 4;"), ints(&[1, 14, 6]));
+    assert_eq!(run_source("let x = 6; push x / 4;"), ints(&[1]));
+    assert_eq!(run_source("push 1; axe; push 2;"), ints(&[1]));
+}
+
+#[test]
+fn mixed_int_and_float_arithmetic_gives_a_float() {
+    assert_eq!(run(vec![Code::Push(1), Code::PushFloat(0.5), Code::Add]), Ok(vec![Value::Float(1.5)]));
+    assert_eq!(run(vec![Code::PushFloat(3.0), Code::Push(2), Code::Div]), Ok(vec![Value::Float(1.5)]));
+    assert_eq!(run(vec![Code::Push(2), Code::PushFloat(0.25), Code::Rooster]), Ok(vec![Value::Float(0.5)]));
+}
+
+#[test]
+fn division_by_zero_reports_the_instruction() {
+    let by_int = vec![Code::Push(1), Code::Push(7), Code::Push(0), Code::Div];
+    assert_eq!(run(by_int), Err(RuntimeError { message: "Division by zero".to_owned(), index: 3 }));
+    let by_float = vec![Code::PushFloat(1.0), Code::PushFloat(0.0), Code::Div];
+    assert_eq!(run(by_float), Err(RuntimeError { message: "Division by zero".to_owned(), index: 2 }));
+}
+
+#[test]
+fn stack_underflow_reports_the_instruction() {
+    let code = vec![Code::Push(1), Code::Push(2), Code::Add, Code::Add];
+    let err = run(code).unwrap_err();
+    assert_eq!(err, RuntimeError { message: "Stack underflow".to_owned(), index: 3 });
+    assert_eq!(err.to_string(), "Stack underflow at instruction 3");
+}
+
+/// The bytes `write_output` gives after `bbq` on each code in turn, joined
+/// with `add`.
+fn bbq_output(mode: BbqMode, codes: &[i64]) -> Result<Vec<u8>, RuntimeError> {
+    let mut code = vec![Code::PushString(String::new())];
+    for &n in codes {
+        code.extend([Code::Push(n), Code::Bbq, Code::Add]);
+    }
+    let mut vm = Vm::with_options(code, VmOptions::default().bbq_mode(mode));
+    vm.run()?;
+    let mut out = Vec::new();
+    vm.write_output(&mut out).unwrap();
+    Ok(out)
+}
+
+#[test]
+fn bbq_modes_write_exact_bytes() {
+    let codes = [72, 233, 255];
+    assert_eq!(bbq_output(BbqMode::Unicode, &codes), Ok(b"H\xC3\xA9\xC3\xBF".to_vec()));
+    assert_eq!(bbq_output(BbqMode::Byte, &codes), Ok(b"H\xE9\xFF".to_vec()));
+    assert_eq!(bbq_output(BbqMode::ChickenCompat, &codes), Ok(b"H&#233;&#255;".to_vec()));
+    assert_eq!(bbq_output(BbqMode::Unicode, &[0x1F414]), Ok("\u{1F414}".as_bytes().to_vec()));
+    assert_eq!(bbq_output(BbqMode::ChickenCompat, &[0x1F414]), Ok(b"&#128020;".to_vec()));
+}
+
+#[test]
+fn bbq_rejects_codes_outside_its_mode() {
+    let error = |message: &str| Err(RuntimeError { message: message.to_owned(), index: 2 });
+    assert_eq!(bbq_output(BbqMode::Unicode, &[0xD800]), error("bbq got a value that isn't a character code"));
+    assert_eq!(bbq_output(BbqMode::Unicode, &[-1]), error("bbq got a value that isn't a character code"));
+    assert_eq!(bbq_output(BbqMode::Byte, &[256]), error("bbq needs a byte from 0 to 255, got 256"));
+    assert_eq!(bbq_output(BbqMode::Byte, &[0x1F414]), error("bbq needs a byte from 0 to 255, got 128020"));
+}
+
+#[test]
+fn each_distinct_string_is_pooled_once() {
+    // Enough distinct strings that a linear search per push would be slow.
+    let texts: Vec<_> = (0..20_000).map(|n| format!("s{}", n)).collect();
+    let code: Vec<_> = texts.iter().chain(&texts).map(|text| Code::PushString(text.clone())).collect();
+    let stack = run(code).unwrap();
+    let (first, second) = stack.split_at(texts.len());
+    for ((a, b), text) in first.iter().zip(second).zip(&texts) {
+        let (Value::Str(a), Value::Str(b)) = (a, b) else { panic!("{:?} {:?}", a, b) };
+        assert_eq!(&**a, text.as_str());
+        assert!(Rc::ptr_eq(a, b), "{}", text);
+    }
+}